@@ -1,6 +1,6 @@
 use soroban_sdk::{contracttype, contracterror, Address, BytesN, Env};
 
-use crate::libraries::timelocks_lib::{Timelocks};
+use crate::libraries::timelocks_lib::{Stage, Timelocks};
 
 // Events
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -73,54 +73,44 @@ pub trait BaseEscrowTrait {
 }
 
 // Helper functions for time validation (semantic equivalent to Solidity's Timelocks.get())
-// impl Immutables {
-//     pub fn is_withdrawal_period(&self, env: &Env, is_source: bool) -> bool {
-//         let stage = if is_source {
-//             Stage::SrcWithdrawal
-//         } else {
-//             Stage::DstWithdrawal
-//         };
-//         let current_time = env.ledger().timestamp();
-//         let withdrawal_start = self.timelocks.get(stage);
-//         current_time >= withdrawal_start.into()
-//     }
+impl Immutables {
+    pub fn is_withdrawal_period(&self, env: &Env, is_source: bool) -> bool {
+        let stage = if is_source {
+            Stage::SrcWithdrawal
+        } else {
+            Stage::DstWithdrawal
+        };
+        self.timelocks.has_started(stage, env.ledger().timestamp())
+    }
     
-//     pub fn is_public_withdrawal_period(&self, env: &Env, is_source: bool) -> bool {
-//         let stage = if is_source {
-//             Stage::SrcPublicWithdrawal
-//         } else {
-//             Stage::DstPublicWithdrawal
-//         };
-//         let current_time = env.ledger().timestamp();
-//         let public_withdrawal_start = self.timelocks.get(stage);
-//         current_time >= public_withdrawal_start.into()
-//     }
+    pub fn is_public_withdrawal_period(&self, env: &Env, is_source: bool) -> bool {
+        let stage = if is_source {
+            Stage::SrcPublicWithdrawal
+        } else {
+            Stage::DstPublicWithdrawal
+        };
+        self.timelocks.has_started(stage, env.ledger().timestamp())
+    }
     
-//     pub fn is_cancellation_period(&self, env: &Env, is_source: bool) -> bool {
-//         let stage = if is_source {
-//             Stage::SrcCancellation
-//         } else {
-//             Stage::DstCancellation
-//         };
-//         let current_time = env.ledger().timestamp();
-//         let cancellation_start = self.timelocks.get(stage);
-//         current_time >= cancellation_start.into()
-//     }
+    pub fn is_cancellation_period(&self, env: &Env, is_source: bool) -> bool {
+        let stage = if is_source {
+            Stage::SrcCancellation
+        } else {
+            Stage::DstCancellation
+        };
+        self.timelocks.has_started(stage, env.ledger().timestamp())
+    }
     
-//     pub fn is_public_cancellation_period(&self, env: &Env) -> bool {
-//         let current_time = env.ledger().timestamp();
-//         let public_cancellation_start = self.timelocks.get(Stage::SrcPublicCancellation);
-//         current_time >= public_cancellation_start.into()
-//     }
+    pub fn is_public_cancellation_period(&self, env: &Env) -> bool {
+        self.timelocks.has_started(Stage::SrcPublicCancellation, env.ledger().timestamp())
+    }
     
-//     pub fn is_rescue_available(&self, env: &Env, rescue_delay: u32) -> bool {
-//         let current_time = env.ledger().timestamp();
-//         let rescue_start = self.timelocks.rescue_start(rescue_delay);
-//         current_time >= rescue_start.into()
-//     }
+    pub fn is_rescue_available(&self, env: &Env, rescue_delay: u32) -> bool {
+        env.ledger().timestamp() >= self.timelocks.rescue_start_timestamp(rescue_delay)
+    }
     
-//     pub fn verify_secret(&self, secret: &BytesN<32>) -> bool {
-//         let computed_hashlock = soroban_sdk::crypto::sha256(&secret.clone().into());
-//         computed_hashlock == self.hashlock
-//     }
-// }
\ No newline at end of file
+    pub fn verify_secret(&self, env: &Env, secret: &BytesN<32>) -> bool {
+        let computed_hashlock: BytesN<32> = env.crypto().sha256(&secret.clone().into()).into();
+        computed_hashlock == self.hashlock
+    }
+}
@@ -1,346 +1,1470 @@
 // lib.rs
 #![no_std]
+use escrow_interface::{
+    derive_hashlock, is_contract_address, merkle_leaf, merkle_root, AmountCalc, ClockSource,
+    EscrowClient, EscrowDirection, EscrowError, EscrowImmutables, EscrowOverview, EscrowResolves,
+    EscrowState, FactoryConfig, FeeRounding, HashAlgo, ProtocolFee, Quote, Rebate, TimeLocks,
+    BPS_DENOMINATOR, DEFAULT_MAX_PARTS, ESCROW_TTL_EXTEND_TO, ESCROW_TTL_THRESHOLD, GUARDIAN_GRACE,
+    MAX_BATCH_QUERY, MAX_ESCROWS_PER_TAKER, VERSION,
+};
 use soroban_sdk::{
-    contract, contracterror, contractimpl, contractmeta, contracttype, panic_with_error, token,
-    Address, Bytes, BytesN, Env, IntoVal, Symbol,
+    contract, contractimpl, contractmeta, panic_with_error, token, vec, Address, Bytes, BytesN,
+    Env, IntoVal, Map, Symbol, Val, Vec,
 };
 
+pub mod interfaces;
+pub mod libraries;
+
 contractmeta!(
     key = "Description",
     val = "Bare-bone cross-chain atomic swap escrow factory"
 );
 
-#[derive(Clone, PartialEq, Debug)]
-#[contracttype]
-pub struct EscrowImmutables {
-    pub hashlock: BytesN<32>, // Hash of the secret
-    pub direction: EscrowDirection,
-    pub maker: Address,
-    pub token: Option<Address>,
-    pub amount: AmountCalc,
-    // pub safety_deposit_token: Address,
-    pub safety_deposit: i128,
-    pub timelocks: TimeLocks, // Timelocks for withdrawal and cancellation
+// Salt of a segment escrow, tagged apart from nonce salts so no escrow created
+// with the order hashlock as its hashlock can take a segment's address
+fn segment_salt(env: &Env, order_hashlock: &BytesN<32>, index: u32) -> BytesN<32> {
+    let mut salt = Bytes::from_slice(env, b"segment");
+    salt.append(&Bytes::from(order_hashlock.clone()));
+    salt.extend_from_array(&index.to_be_bytes());
+    env.crypto().sha256(&salt).to_bytes()
 }
 
-#[derive(Clone, PartialEq, Debug)]
-#[contracttype]
-pub enum EscrowDirection {
-    Maker2Taker,
-    Taker2Maker,
+// Salt of an escrow deployed for a hashlock and a nonce
+fn nonce_salt(env: &Env, hashlock: &BytesN<32>, nonce: u64) -> BytesN<32> {
+    let mut salt = Bytes::from(hashlock.clone());
+    salt.extend_from_array(&nonce.to_be_bytes());
+    env.crypto().sha256(&salt).to_bytes()
 }
 
-#[derive(Clone, PartialEq, Debug)]
-#[contracttype]
-pub enum AmountCalc {
-    Flat(i128),
-    Linear(DutchAuction),
-}
+#[contract]
+pub struct EscrowFactory;
 
-impl AmountCalc {
-    pub fn calc(&self, timestamp: u64) -> i128 {
-        match self {
-            AmountCalc::Flat(amount) => *amount,
-            AmountCalc::Linear(da) => {
-                let ts = timestamp.clamp(da.start_time, da.stop_time);
-                let a = da.start_amount * (da.stop_time - ts) as i128;
-                let b = da.stop_amount * (ts - da.start_time) as i128;
-                (a + b) / (da.stop_time - da.start_time) as i128
-            }
+#[contractimpl]
+impl EscrowFactory {
+    // Initialize factory with its admin and the wasm its escrows are deployed from
+    pub fn __constructor(
+        env: Env,
+        admin: Address,
+        topic_prefix: Symbol,
+        escrow_wasm_hash: BytesN<32>,
+    ) {
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "admin"), &admin);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "topic_prefix"), &topic_prefix);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "escrow_wasm_hash"), &escrow_wasm_hash);
+    }
+
+    // Create a new escrow for atomic swap
+    pub fn create_escrow(
+        env: Env,
+        immutables: EscrowImmutables,
+        nonce: u64,
+        taker: Address,
+    ) -> Address {
+        // Deploy new escrow contract with deterministic address
+        let salt = nonce_salt(&env, &immutables.hashlock, nonce);
+
+        Self::deploy_escrow(&env, salt, &immutables, immutables.clone(), taker, None)
+    }
+
+    // Create a destination escrow, which the taker must be able to cancel
+    // strictly before the maker can reclaim the source escrow
+    pub fn create_dst_escrow(
+        env: Env,
+        immutables: EscrowImmutables,
+        nonce: u64,
+        taker: Address,
+        src_cancellation_timestamp: u64,
+    ) -> Address {
+        let cancellation = env
+            .ledger()
+            .timestamp()
+            .checked_add(immutables.timelocks.cancellation)
+            .unwrap_or_else(|| panic_with_error!(&env, EscrowError::InvalidCreationTime));
+        if cancellation >= src_cancellation_timestamp {
+            panic_with_error!(&env, EscrowError::InvalidCreationTime);
         }
+
+        Self::create_escrow(env, immutables, nonce, taker)
     }
-}
 
-#[derive(Clone, PartialEq, Debug)]
-#[contracttype]
-pub struct DutchAuction {
-    pub start_time: u64,
-    pub stop_time: u64,
-    pub start_amount: i128,
-    pub stop_amount: i128,
-}
+    // Create an escrow for one segment of an order split into parts, the order
+    // hashlock being the Merkle root of the segment hashlocks and amounts
+    pub fn create_escrow_for_segment(
+        env: Env,
+        immutables: EscrowImmutables,
+        merkle_proof: Vec<BytesN<32>>,
+        index: u32,
+        segment_hashlock: BytesN<32>,
+        segment_amount: i128,
+        taker: Address,
+    ) -> Address {
+        // Validate the segment index, each index deploying at most one escrow
+        // bounds the escrows and fills of an order to max_parts
+        if index >= Self::max_parts(env.clone()) {
+            panic_with_error!(&env, EscrowError::TooManyParts);
+        }
 
-#[derive(Clone, PartialEq, Debug)]
-#[contracttype]
-pub struct TimeLocks {
-    pub withdrawal: u64,
-    pub public_withdrawal: u64,
-    pub cancellation: u64,
-    pub public_cancellation: u64,
-}
+        // Validate the maker hasn't cancelled the segment
+        if Self::is_segment_cancelled(env.clone(), immutables.clone(), index) {
+            panic_with_error!(&env, EscrowError::SegmentCancelled);
+        }
 
-#[derive(Clone)]
-#[contracttype]
-pub struct EscrowResolves {
-    taker: Address,
-    amount: i128,
-    timestamp: u64,
-}
+        // Validate the order's amount calculation, the escrow deployed only seeing
+        // the segment's flat amount
+        if !immutables.amount.is_valid() {
+            panic_with_error!(&env, EscrowError::InvalidAmount);
+        }
 
-#[derive(Clone, PartialEq, Debug)]
-#[contracttype]
-pub enum EscrowState {
-    Active,
-    Withdrawn,
-    Cancelled,
-}
+        // Validate the segment and its amount belong to the order
+        let leaf = merkle_leaf(&env, index, segment_amount, &segment_hashlock);
+        if merkle_root(&env, leaf, &merkle_proof) != immutables.hashlock {
+            panic_with_error!(&env, EscrowError::InvalidMerkleProof);
+        }
 
-#[contracterror]
-#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
-#[repr(u32)]
-pub enum EscrowError {
-    AlreadyTaken = 1,
-    NotActive = 2,
-    Unauthorized = 3,
-    TooEarly = 4,
-    InvalidSecret = 5,
-}
+        // Validate the order doesn't get overfilled
+        let filled_key = (Symbol::new(&env, "filled"), immutables.hashlock.clone());
+        let filled: i128 = env.storage().persistent().get(&filled_key).unwrap_or(0);
+        let total = Self::current_amount(&env, &immutables.amount);
+        if segment_amount <= 0 || filled + segment_amount > total {
+            panic_with_error!(&env, EscrowError::InvalidPartialFill);
+        }
+        env.storage()
+            .persistent()
+            .set(&filled_key, &(filled + segment_amount));
+        Self::bump_persistent(&env, &filled_key);
+        let segment_key = (
+            Symbol::new(&env, "segment"),
+            immutables.hashlock.clone(),
+            index,
+        );
+        env.storage().persistent().set(&segment_key, &true);
+        Self::bump_persistent(&env, &segment_key);
 
-#[contract]
-pub struct EscrowFactory;
+        // Segment escrow is locked by the segment hashlock
+        let mut segment = immutables.clone();
+        segment.hashlock = segment_hashlock;
+        segment.amount = AmountCalc::Flat(segment_amount);
 
-#[contractimpl]
-impl EscrowFactory {
-    // Create a new escrow for atomic swap
-    pub fn create_escrow(env: Env, immutables: EscrowImmutables, taker: Address) -> Address {
-        // Deploy new escrow contract with deterministic address
-        let salt = immutables.hashlock.clone();
+        // Deploy segment escrow salted by the order hashlock and index
+        let salt = segment_salt(&env, &immutables.hashlock, index);
 
-        let address = env
-            .deployer()
-            .with_current_contract(salt)
-            .deployed_address();
+        Self::deploy_escrow(&env, salt, &immutables, segment, taker, Some(index))
+    }
 
-        taker.require_auth();
+    // Cancel the unfilled segments start..end of an order, leaving the others fillable.
+    // The maker's tokens only leave their account when a segment escrow is created,
+    // so the cancelled segments hold nothing to refund
+    pub fn cancel_segments(env: Env, immutables: EscrowImmutables, start: u32, end: u32) {
+        immutables.maker.require_auth();
 
-        let sender = match immutables.direction {
-            EscrowDirection::Maker2Taker => {
-                immutables
-                    .maker
-                    .require_auth_for_args((immutables.clone(),).into_val(&env));
-                &immutables.maker
+        // Validate the range is within the order and none of it is filled
+        if start >= end || end > Self::max_parts(env.clone()) {
+            panic_with_error!(&env, EscrowError::InvalidPartialFill);
+        }
+        let order_hash = Self::order_hash_of(env.clone(), immutables.clone());
+        for index in start..end {
+            if env.storage().persistent().has(&(
+                Symbol::new(&env, "segment"),
+                immutables.hashlock.clone(),
+                index,
+            )) {
+                panic_with_error!(&env, EscrowError::InvalidPartialFill);
             }
-            EscrowDirection::Taker2Maker => &taker,
-        };
+            let cancelled_key = (
+                Symbol::new(&env, "seg_cancelled"),
+                order_hash.clone(),
+                index,
+            );
+            env.storage().persistent().set(&cancelled_key, &true);
+            Self::bump_persistent(&env, &cancelled_key);
+        }
+
+        // Emit event
+        env.events().publish(
+            (
+                Self::topic_prefix(env.clone()),
+                Symbol::new(&env, "cancel_segments"),
+                immutables.hashlock,
+            ),
+            (start, end),
+        );
+    }
+
+    // Check whether the maker cancelled a segment of an order
+    pub fn is_segment_cancelled(env: Env, immutables: EscrowImmutables, index: u32) -> bool {
+        let order_hash = Self::order_hash_of(env.clone(), immutables);
+        env.storage()
+            .persistent()
+            .has(&(Symbol::new(&env, "seg_cancelled"), order_hash, index))
+    }
+
+    // Record a segment withdrawal and its secret reported by its escrow, returning
+    // the amount withdrawn across the order so far
+    pub fn record_fill(
+        env: Env,
+        escrow: Address,
+        order_hashlock: BytesN<32>,
+        amount: i128,
+        secret: Bytes,
+    ) -> i128 {
+        Self::require_escrow(&env, &escrow);
+
+        let secrets_key = (Symbol::new(&env, "secrets"), order_hashlock.clone());
+        let mut secrets = Self::revealed_secrets(env.clone(), order_hashlock.clone());
+        secrets.push_back(secret);
+        env.storage().persistent().set(&secrets_key, &secrets);
+        Self::bump_persistent(&env, &secrets_key);
 
+        let withdrawn_key = (Symbol::new(&env, "withdrawn"), order_hashlock);
+        let withdrawn: i128 = env.storage().persistent().get(&withdrawn_key).unwrap_or(0);
+        env.storage()
+            .persistent()
+            .set(&withdrawn_key, &(withdrawn + amount));
+        Self::bump_persistent(&env, &withdrawn_key);
+        withdrawn + amount
+    }
+
+    // Get the secrets revealed by the segment escrows of an order, in withdrawal order
+    pub fn revealed_secrets(env: Env, order_hashlock: BytesN<32>) -> Vec<Bytes> {
+        env.storage()
+            .persistent()
+            .get(&(Symbol::new(&env, "secrets"), order_hashlock))
+            .unwrap_or(vec![&env])
+    }
+
+    // Record a public withdrawal reported by an escrow, rejecting callers
+    // withdrawing again within the public cooldown. The escrow authenticates the
+    // caller, so the cooldown can't be dodged by naming another address
+    pub fn record_public_withdrawal(env: Env, escrow: Address, caller: Address) {
+        Self::require_escrow(&env, &escrow);
+
+        let cooldown = Self::public_cooldown(env.clone());
+        if cooldown == 0 {
+            return;
+        }
+
+        // Last withdrawals only matter for the cooldown, so they're kept temporary
         let timestamp = env.ledger().timestamp();
+        let last_key = (Symbol::new(&env, "public_call"), caller);
+        if let Some(last) = env.storage().temporary().get::<_, u64>(&last_key) {
+            if timestamp < last.saturating_add(cooldown) {
+                panic_with_error!(&env, EscrowError::CooldownActive);
+            }
+        }
+        env.storage().temporary().set(&last_key, &timestamp);
+        env.storage()
+            .temporary()
+            .extend_ttl(&last_key, ESCROW_TTL_THRESHOLD, ESCROW_TTL_EXTEND_TO);
+    }
 
-        let amount = immutables.amount.calc(timestamp);
+    // Record the principal settled by an escrow towards the volume of its token,
+    // as withdrawn or refunded
+    pub fn record_settlement(
+        env: Env,
+        escrow: Address,
+        token: Address,
+        amount: i128,
+        withdrawn: bool,
+    ) {
+        Self::require_escrow(&env, &escrow);
 
-        let lumens_client = token::Client::new(&env, &env.current_contract_address());
+        let (withdrawn_volume, refunded_volume) = Self::volume_of(env.clone(), token.clone());
+        let volume = if withdrawn {
+            (withdrawn_volume + amount, refunded_volume)
+        } else {
+            (withdrawn_volume, refunded_volume + amount)
+        };
+        env.storage()
+            .persistent()
+            .set(&(Symbol::new(&env, "volume"), token), &volume);
+    }
+
+    // Record the protocol fee an escrow sent to the factory on withdrawal
+    pub fn record_fee(env: Env, escrow: Address, token: Address, amount: i128) {
+        Self::require_escrow(&env, &escrow);
 
-        let token_client = match immutables.token {
-            Some(ref token) => &token::Client::new(&env, token),
-            None => &lumens_client,
+        let fees = Self::collected_fees(env.clone(), token.clone());
+        env.storage()
+            .persistent()
+            .set(&(Symbol::new(&env, "fees"), token), &(fees + amount));
+    }
+
+    // Pay the rebate to a taker withdrawing from an escrow, if within the prompt window
+    // and the pool can cover it, returning the amount paid
+    pub fn pay_rebate(env: Env, escrow: Address, taker: Address, withdrawal_start: u64) -> i128 {
+        Self::require_escrow(&env, &escrow);
+
+        let rebate = match Self::rebate(env.clone()) {
+            Some(rebate) => rebate,
+            None => return 0,
         };
+        let pool = Self::rebate_pool(env.clone(), rebate.token.clone());
+        let prompt =
+            env.ledger().timestamp() < withdrawal_start.saturating_add(rebate.prompt_window);
+        if !prompt || pool < rebate.amount {
+            return 0;
+        }
 
-        // Transfer tokens to escrow
-        token_client.transfer(sender, &address, &amount);
+        env.storage().persistent().set(
+            &(Symbol::new(&env, "rebate_pool"), rebate.token.clone()),
+            &(pool - rebate.amount),
+        );
+        token::Client::new(&env, &rebate.token).transfer(
+            &env.current_contract_address(),
+            &taker,
+            &rebate.amount,
+        );
+        rebate.amount
+    }
 
-        // Transfer safety deposit
-        lumens_client.transfer(&taker, &address, &immutables.safety_deposit);
+    // Predict the address of an escrow created with a nonce, without its immutables
+    pub fn predict_address(env: Env, hashlock: BytesN<32>, nonce: u64) -> Address {
+        env.deployer()
+            .with_current_contract(nonce_salt(&env, &hashlock, nonce))
+            .deployed_address()
+    }
 
-        // Initialize escrow contracts
-        // env.register_at(&address, Escrow, ());
+    // Predict the address of the escrow of a segment of an order
+    pub fn predict_segment_address(env: Env, order_hashlock: BytesN<32>, index: u32) -> Address {
+        env.deployer()
+            .with_current_contract(segment_salt(&env, &order_hashlock, index))
+            .deployed_address()
+    }
+
+    // Deploy an escrow for the part of an escrow split off to another taker, which
+    // funds it and passes its resolves as it can't be called back
+    pub fn deploy_split(
+        env: Env,
+        escrow: Address,
+        resolves: EscrowResolves,
+        immutables: EscrowImmutables,
+        amount: i128,
+        nonce: u64,
+        taker: Address,
+    ) -> Address {
+        Self::require_escrow(&env, &escrow);
+
+        // Validate the new taker is an account, if required
+        Self::validate_accounts(&env, vec![&env, taker.clone()]);
+
+        // Validate neither part of the split is dust
+        let min_transfer = Self::min_transfer(env.clone(), immutables.token.clone());
+        if amount < min_transfer || resolves.amount - amount < min_transfer {
+            panic_with_error!(&env, EscrowError::BelowMinTransfer);
+        }
+
+        let salt = nonce_salt(&env, &immutables.hashlock, nonce);
+        let address = env
+            .deployer()
+            .with_current_contract(salt.clone())
+            .deployed_address();
+
+        Self::index_escrow(&env, &address, &taker);
+
+        // Initialize escrow contracts, the split part filling no segment of its own
+        // and earning no rebate, both of which stay with the escrow split
+        Self::deploy_at(&env, salt, &address);
         EscrowClient::new(&env, &address).initialize(
             &immutables,
             &EscrowResolves {
                 taker,
                 amount,
-                timestamp,
+                safety_deposit: immutables.safety_deposit.amount(&env, amount),
+                segment_index: None,
+                rebate: false,
+                ..resolves
             },
         );
 
+        // Emit event
+        env.events().publish(
+            (
+                Self::topic_prefix(env.clone()),
+                Symbol::new(&env, "escrow_split"),
+                address.clone(),
+            ),
+            (escrow, amount),
+        );
+
         address
     }
-}
 
-#[contract]
-pub struct Escrow;
+    // Get the principal and safety deposit create_escrow would pull at the current timestamp,
+    // extra safety deposits are pulled as listed in the immutables
+    pub fn required_funding(env: Env, immutables: EscrowImmutables) -> (i128, i128) {
+        let amount = Self::current_amount(&env, &immutables.amount);
+        (
+            amount,
+            if Self::no_safety_deposit(env.clone()) {
+                0
+            } else {
+                immutables.safety_deposit.amount(&env, amount)
+            },
+        )
+    }
 
-#[contractimpl]
-impl Escrow {
-    // Initialize escrow with immutables
-    pub fn initialize(env: Env, immutables: EscrowImmutables, resolves: EscrowResolves) {
-        if env.storage().instance().has(&Symbol::new(&env, "state")) {
-            panic_with_error!(&env, EscrowError::AlreadyTaken);
+    // Get the principal, safety deposit, window schedule and address of an escrow
+    // created now from the immutables and the nonce
+    pub fn quote(env: Env, immutables: EscrowImmutables, nonce: u64) -> Quote {
+        let (amount, safety_deposit) = Self::required_funding(env.clone(), immutables.clone());
+        let now = env.ledger().timestamp();
+        let timelocks = &immutables.timelocks;
+        Quote {
+            amount,
+            safety_deposit,
+            timelocks: TimeLocks {
+                withdrawal: now.saturating_add(timelocks.withdrawal),
+                public_withdrawal: now.saturating_add(timelocks.public_withdrawal),
+                cancellation: now.saturating_add(timelocks.cancellation),
+                public_cancellation: now.saturating_add(timelocks.public_cancellation),
+            },
+            address: Self::predict_address(env.clone(), immutables.hashlock, nonce),
         }
+    }
+
+    // Get the principal of an escrow created at the given time, in units of the clock
+    // source, so clients can chart the auction curve
+    pub fn quote_at(env: Env, immutables: EscrowImmutables, timestamp: u64) -> i128 {
+        if !immutables.amount.is_valid() {
+            panic_with_error!(&env, EscrowError::InvalidAmount);
+        }
+
+        immutables
+            .amount
+            .checked_calc(timestamp)
+            .unwrap_or_else(|| panic_with_error!(&env, EscrowError::AmountOverflow))
+    }
+
+    // Hash of the canonical encoding of an escrow, every field included
+    pub fn order_hash(env: Env, immutables: EscrowImmutables) -> BytesN<32> {
+        env.crypto().sha256(&immutables.encode(&env)).to_bytes()
+    }
+
+    // Hash of the order-defining fields, correlating escrows with off-chain orders
+    pub fn order_hash_of(env: Env, immutables: EscrowImmutables) -> BytesN<32> {
+        env.crypto()
+            .sha256(&immutables.encode_order(&env))
+            .to_bytes()
+    }
+
+    // Publish a secret revealed by the withdrawal of an escrow, blocking cancellation
+    // of every escrow locked by its hashlock
+    pub fn reveal(env: Env, escrow: Address, secret: Bytes, algo: HashAlgo) {
+        Self::require_escrow(&env, &escrow);
+
+        let hashlock = algo.hash(&env, &secret);
+
+        env.storage()
+            .persistent()
+            .set(&(Symbol::new(&env, "revealed"), hashlock.clone()), &true);
+
+        // Emit event
+        env.events().publish(
+            (
+                Self::topic_prefix(env.clone()),
+                Symbol::new(&env, "reveal"),
+                hashlock,
+            ),
+            (secret,),
+        );
+    }
+
+    // Mark an escrow locked by the hashlock as settling until end_settlement, failing
+    // while another is. The host rejects a contract re-entering the escrow itself, the
+    // marker keeps a call made mid-transfer from settling a sibling escrow instead
+    pub fn begin_settlement(env: Env, escrow: Address, hashlock: BytesN<32>) {
+        Self::require_escrow(&env, &escrow);
+
+        let key = (Symbol::new(&env, "settling"), hashlock);
+        if env.storage().temporary().has(&key) {
+            panic_with_error!(&env, EscrowError::SettlementInProgress);
+        }
+        env.storage().temporary().set(&key, &escrow);
+    }
+
+    // Clear the settlement marker an escrow set for the hashlock
+    pub fn end_settlement(env: Env, escrow: Address, hashlock: BytesN<32>) {
+        Self::require_escrow(&env, &escrow);
+
+        let key = (Symbol::new(&env, "settling"), hashlock);
+        let settling: Option<Address> = env.storage().temporary().get(&key);
+        if settling != Some(escrow) {
+            panic_with_error!(&env, EscrowError::Unauthorized);
+        }
+        env.storage().temporary().remove(&key);
+    }
+
+    // Check whether the secret of a hashlock has been revealed
+    pub fn is_revealed(env: Env, hashlock: BytesN<32>) -> bool {
+        env.storage()
+            .persistent()
+            .has(&(Symbol::new(&env, "revealed"), hashlock))
+    }
+
+    // Compute the hashlock of a secret with the given algorithm
+    pub fn compute_hashlock(env: Env, secret: Bytes, algo: HashAlgo) -> BytesN<32> {
+        derive_hashlock(&env, &secret, &algo)
+    }
+
+    // Set how long withdrawn funds stay pending before release, 0 disables
+    pub fn set_dispute_window(env: Env, dispute_window: u64) {
+        Self::require_admin(&env);
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "dispute_window"), &dispute_window);
+    }
+
+    // Get the dispute window applied to new escrows
+    pub fn dispute_window(env: Env) -> u64 {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "dispute_window"))
+            .unwrap_or(0)
+    }
+
+    // Set how long the amount locked by new escrows stays valid before the taker
+    // may reprice it at the current auction level, 0 disables
+    pub fn set_amount_validity(env: Env, amount_validity: u64) {
+        Self::require_admin(&env);
 
         env.storage()
             .instance()
-            .set(&Symbol::new(&env, "state"), &EscrowState::Active);
+            .set(&Symbol::new(&env, "amount_validity"), &amount_validity);
+    }
+
+    // Get the amount validity applied to new escrows
+    pub fn amount_validity(env: Env) -> u64 {
         env.storage()
             .instance()
-            .set(&Symbol::new(&env, "immutables"), &immutables);
+            .get(&Symbol::new(&env, "amount_validity"))
+            .unwrap_or(0)
+    }
+
+    // Set the delay past the opening of a public window over which the public caller's
+    // reward grows linearly to the full safety deposit, 0 pays it in full right away
+    pub fn set_reward_ramp(env: Env, reward_ramp: u64) {
+        Self::require_admin(&env);
+
         env.storage()
             .instance()
-            .set(&Symbol::new(&env, "resolves"), &resolves);
+            .set(&Symbol::new(&env, "reward_ramp"), &reward_ramp);
     }
 
-    // Withdraw funds with secret
-    pub fn withdraw(env: Env, secret: Bytes, caller: Address) {
-        let immutables: EscrowImmutables = env
-            .storage()
+    // Get the reward ramp applied to new escrows
+    pub fn reward_ramp(env: Env) -> u64 {
+        env.storage()
             .instance()
-            .get(&Symbol::new(&env, "immutables"))
-            .unwrap();
+            .get(&Symbol::new(&env, "reward_ramp"))
+            .unwrap_or(0)
+    }
+
+    // Set whether cancelling new escrows before public cancellation opens also
+    // needs the maker's auth
+    pub fn set_maker_auth_cancel(env: Env, maker_auth_cancel: bool) {
+        Self::require_admin(&env);
 
-        let resolves: EscrowResolves = env
-            .storage()
+        env.storage()
             .instance()
-            .get(&Symbol::new(&env, "resolves"))
-            .unwrap();
+            .set(&Symbol::new(&env, "maker_auth_cancel"), &maker_auth_cancel);
+    }
 
-        let state: EscrowState = env
-            .storage()
+    // Get whether private cancellation of new escrows needs the maker's auth
+    pub fn maker_auth_cancel(env: Env) -> bool {
+        env.storage()
             .instance()
-            .get(&Symbol::new(&env, "state"))
-            .unwrap();
+            .get(&Symbol::new(&env, "maker_auth_cancel"))
+            .unwrap_or(false)
+    }
 
-        let sender = env.current_contract_address();
+    // Disable the safety deposit mechanism of new escrows for permissioned deployments,
+    // deposits listed in the immutables are then neither pulled nor paid out
+    pub fn set_no_safety_deposit(env: Env, no_safety_deposit: bool) {
+        Self::require_admin(&env);
 
-        let payee = match immutables.direction {
-            EscrowDirection::Maker2Taker => &resolves.taker,
-            EscrowDirection::Taker2Maker => &immutables.maker,
-        };
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "no_safety_deposit"), &no_safety_deposit);
+    }
+
+    // Get whether new escrows are created without safety deposits
+    pub fn no_safety_deposit(env: Env) -> bool {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "no_safety_deposit"))
+            .unwrap_or(false)
+    }
+
+    // Set how far past creation any timelock of new escrows may end, 0 disables
+    pub fn set_max_future(env: Env, max_future: u64) {
+        Self::require_admin(&env);
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "max_future"), &max_future);
+    }
 
-        // Validate state
-        if !matches!(state, EscrowState::Active) {
-            panic_with_error!(&env, EscrowError::NotActive);
+    // Get how far past creation any timelock of new escrows may end
+    pub fn max_future(env: Env) -> u64 {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "max_future"))
+            .unwrap_or(0)
+    }
+
+    // Set the fee new escrows take from the principal on withdrawal, at most the whole of it
+    pub fn set_protocol_fee(env: Env, protocol_fee: ProtocolFee) {
+        Self::require_admin(&env);
+
+        if protocol_fee.bps as i128 > BPS_DENOMINATOR {
+            panic_with_error!(&env, EscrowError::InvalidAmount);
+        }
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "protocol_fee"), &protocol_fee);
+    }
+
+    // Get the fee new escrows take from the principal on withdrawal
+    pub fn protocol_fee(env: Env) -> ProtocolFee {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "protocol_fee"))
+            .unwrap_or(ProtocolFee {
+                bps: 0,
+                rounding: FeeRounding::RoundDown,
+            })
+    }
+
+    // Get the protocol fees collected in a token and not yet withdrawn
+    pub fn collected_fees(env: Env, token: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&(Symbol::new(&env, "fees"), token))
+            .unwrap_or(0)
+    }
+
+    // Withdraw the protocol fees collected in a token, returning the amount sent
+    pub fn withdraw_fees(env: Env, token: Address, to: Address) -> i128 {
+        Self::require_admin(&env);
+
+        let fees = Self::collected_fees(env.clone(), token.clone());
+        env.storage()
+            .persistent()
+            .set(&(Symbol::new(&env, "fees"), token.clone()), &0i128);
+        if fees > 0 {
+            token::Client::new(&env, &token).transfer(&env.current_contract_address(), &to, &fees);
+        }
+        fees
+    }
+
+    // Require makers, takers and backup takers of new escrows to be accounts rather
+    // than contracts, for deployments wary of callbacks from the parties
+    pub fn set_require_eoa(env: Env, require_eoa: bool) {
+        Self::require_admin(&env);
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "require_eoa"), &require_eoa);
+    }
+
+    // Get whether the parties of new escrows must be accounts
+    pub fn require_eoa(env: Env) -> bool {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "require_eoa"))
+            .unwrap_or(false)
+    }
+
+    // Set the registry new escrows push their secrets to on withdrawal, letting
+    // keepers settle sibling escrows by reading it instead of watching events
+    pub fn set_secret_registry(env: Env, secret_registry: Address) {
+        Self::require_admin(&env);
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "secret_registry"), &secret_registry);
+    }
+
+    // Get the registry secrets are pushed to, if configured
+    pub fn secret_registry(env: Env) -> Option<Address> {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "secret_registry"))
+    }
+
+    // Set the guardian allowed to force-cancel escrows that never settled
+    pub fn set_guardian(env: Env, guardian: Address) {
+        Self::require_admin(&env);
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "guardian"), &guardian);
+    }
+
+    // Get the guardian, if configured
+    pub fn guardian(env: Env) -> Option<Address> {
+        env.storage().instance().get(&Symbol::new(&env, "guardian"))
+    }
+
+    // Force-cancel an escrow of this factory still active GUARDIAN_GRACE past its
+    // public cancellation, as a last resort when nobody could settle it. Returns
+    // whether the principal was recovered
+    pub fn guardian_cancel(env: Env, escrow: Address) -> bool {
+        // Require the guardian's auth
+        let guardian = Self::guardian(env.clone())
+            .unwrap_or_else(|| panic_with_error!(&env, EscrowError::Unauthorized));
+        guardian.require_auth();
+
+        // Validate the escrow is one this factory deployed before calling into it
+        if !Self::is_my_escrow(env.clone(), escrow.clone()) {
+            panic_with_error!(&env, EscrowError::Unauthorized);
         }
 
         // Validate time
-        let start = resolves.timestamp
-            + if caller == resolves.taker {
-                immutables.timelocks.withdrawal
-            } else {
-                immutables.timelocks.public_withdrawal
-            };
-        let timestamp = env.ledger().timestamp();
-        if timestamp < start || timestamp >= immutables.timelocks.cancellation {
+        let escrow = EscrowClient::new(&env, &escrow);
+        let immutables = escrow.get_immutables();
+        let resolves = escrow.get_resolves();
+        let grace_end = resolves
+            .timestamp
+            .saturating_add(immutables.timelocks.public_cancellation)
+            .saturating_add(GUARDIAN_GRACE);
+        if env.ledger().timestamp() < grace_end {
             panic_with_error!(&env, EscrowError::TooEarly);
         }
 
-        // Validate secret
-        let secret_hash = env.crypto().sha256(&secret);
-        if secret_hash.to_bytes() != immutables.hashlock {
-            panic_with_error!(&env, EscrowError::InvalidSecret);
+        // Validate the secrets aren't all revealed, as the counterparty leg may have paid
+        // out already. The escrow can't call back to check it itself
+        if Self::secrets_revealed(&env, &immutables, &resolves) {
+            panic_with_error!(&env, EscrowError::SecretRevealed);
         }
 
-        let lumens_client = token::Client::new(&env, &env.current_contract_address());
+        escrow.force_cancel()
+    }
 
-        let token_client = match immutables.token {
-            Some(ref token) => &token::Client::new(&env, token),
-            None => &lumens_client,
-        };
+    // Set the ed25519 key whose attestations let new destination escrows be cancelled
+    // as soon as their source escrow is
+    pub fn set_cancel_oracle(env: Env, cancel_oracle: BytesN<32>) {
+        Self::require_admin(&env);
 
-        // Transfer tokens
-        token_client.transfer(&sender, payee, &resolves.amount);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "cancel_oracle"), &cancel_oracle);
+    }
 
-        // Transfer safety deposit to caller
-        lumens_client.transfer(&sender, &caller, &immutables.safety_deposit);
+    // Get the key attesting source cancellations, if configured
+    pub fn cancel_oracle(env: Env) -> Option<BytesN<32>> {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "cancel_oracle"))
+    }
+
+    // Set the clock Dutch auctions of new escrows are evaluated against
+    pub fn set_clock_source(env: Env, clock_source: ClockSource) {
+        Self::require_admin(&env);
 
-        // Update state
         env.storage()
             .instance()
-            .set(&Symbol::new(&env, "state"), &EscrowState::Withdrawn);
+            .set(&Symbol::new(&env, "clock_source"), &clock_source);
+    }
 
-        // Emit event
-        env.events()
-            .publish((Symbol::new(&env, "withdraw"),), (secret,));
+    // Get the clock Dutch auctions are evaluated against
+    pub fn clock_source(env: Env) -> ClockSource {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "clock_source"))
+            .unwrap_or(ClockSource::Timestamp)
     }
 
-    // Cancel escrow and return funds
-    pub fn cancel(env: Env, caller: Address) {
-        let immutables: EscrowImmutables = env
-            .storage()
+    // Set the rebate paid to prompt takers, replacing any previous one
+    pub fn set_rebate(env: Env, rebate: Rebate) {
+        Self::require_admin(&env);
+
+        env.storage()
             .instance()
-            .get(&Symbol::new(&env, "immutables"))
-            .unwrap();
+            .set(&Symbol::new(&env, "rebate"), &rebate);
+    }
+
+    // Get the rebate paid to prompt takers
+    pub fn rebate(env: Env) -> Option<Rebate> {
+        env.storage().instance().get(&Symbol::new(&env, "rebate"))
+    }
+
+    // Fund the rebate pool of a token, which can happen before the rebate is set to
+    // pay in that token so it starts out funded
+    pub fn fund_rebate_pool(env: Env, funder: Address, token: Address, amount: i128) {
+        funder.require_auth();
 
-        let resolves: EscrowResolves = env
-            .storage()
+        if amount <= 0 {
+            panic_with_error!(&env, EscrowError::InvalidAmount);
+        }
+
+        token::Client::new(&env, &token).transfer(
+            &funder,
+            &env.current_contract_address(),
+            &amount,
+        );
+        let pool = Self::rebate_pool(env.clone(), token.clone());
+        env.storage().persistent().set(
+            &(Symbol::new(&env, "rebate_pool"), token),
+            &(pool + amount),
+        );
+    }
+
+    // Get the amount left in the rebate pool of a token, pools of former rebate
+    // tokens being paid from again should the rebate switch back to them
+    pub fn rebate_pool(env: Env, token: Address) -> i128 {
+        env.storage()
+            .persistent()
+            .get(&(Symbol::new(&env, "rebate_pool"), token))
+            .unwrap_or(0)
+    }
+
+    // Set the ledgers escrows extend their storage lifetime to on each state change, 0 disables
+    pub fn set_ttl_bump(env: Env, ttl_bump: u32) {
+        Self::require_admin(&env);
+
+        env.storage()
             .instance()
-            .get(&Symbol::new(&env, "resolves"))
-            .unwrap();
+            .set(&Symbol::new(&env, "ttl_bump"), &ttl_bump);
+    }
 
-        let state: EscrowState = env
-            .storage()
+    // Get the ledgers escrows extend their storage lifetime to on each state change
+    pub fn ttl_bump(env: Env) -> u32 {
+        env.storage()
             .instance()
-            .get(&Symbol::new(&env, "state"))
-            .unwrap();
+            .get(&Symbol::new(&env, "ttl_bump"))
+            .unwrap_or(ESCROW_TTL_EXTEND_TO)
+    }
 
-        let sender = env.current_contract_address();
+    // Set the reward new escrows pay out of their safety deposit to keepers cancelling
+    // them once public cancellation is open, capped at the deposit
+    pub fn set_keeper_reward(env: Env, keeper_reward: i128) {
+        Self::require_admin(&env);
 
-        let payee = match immutables.direction {
-            EscrowDirection::Maker2Taker => &immutables.maker,
-            EscrowDirection::Taker2Maker => &resolves.taker,
-        };
+        if keeper_reward < 0 {
+            panic_with_error!(&env, EscrowError::InvalidAmount);
+        }
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "keeper_reward"), &keeper_reward);
+    }
+
+    // Get the reward paid to keepers cancelling escrows
+    pub fn keeper_reward(env: Env) -> i128 {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "keeper_reward"))
+            .unwrap_or(0)
+    }
+
+    // Set the minimum interval between public withdrawals by the same caller, 0 disables
+    pub fn set_public_cooldown(env: Env, public_cooldown: u64) {
+        Self::require_admin(&env);
 
-        // Validate state
-        if !matches!(state, EscrowState::Active) {
-            panic_with_error!(&env, EscrowError::NotActive);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "public_cooldown"), &public_cooldown);
+    }
+
+    // Get the minimum interval between public withdrawals by the same caller
+    pub fn public_cooldown(env: Env) -> u64 {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "public_cooldown"))
+            .unwrap_or(0)
+    }
+
+    // Set the smallest amount an escrow can lock, 0 disables
+    pub fn set_min_amount(env: Env, min_amount: i128) {
+        Self::require_admin(&env);
+
+        if min_amount < 0 {
+            panic_with_error!(&env, EscrowError::InvalidAmount);
         }
 
-        // Validate time
-        let start = resolves.timestamp
-            + if caller == resolves.taker {
-                immutables.timelocks.cancellation
-            } else {
-                immutables.timelocks.public_cancellation
-            };
-        if env.ledger().timestamp() < start {
-            panic_with_error!(&env, EscrowError::TooEarly);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "min_amount"), &min_amount);
+    }
+
+    // Get the smallest amount an escrow can lock
+    pub fn min_amount(env: Env) -> i128 {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "min_amount"))
+            .unwrap_or(0)
+    }
+
+    // Set the smallest amount of a token escrows may transfer, 0 disables
+    pub fn set_min_transfer(env: Env, token: Address, min_transfer: i128) {
+        Self::require_admin(&env);
+
+        if min_transfer < 0 {
+            panic_with_error!(&env, EscrowError::InvalidAmount);
         }
 
-        // Require caller's auth
-        caller.require_auth();
+        let mut min_transfers = Self::min_transfers(env.clone());
+        min_transfers.set(token, min_transfer);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "min_transfers"), &min_transfers);
+    }
 
-        let lumens_client = token::Client::new(&env, &env.current_contract_address());
+    // Get the smallest amount of a token escrows may transfer
+    pub fn min_transfer(env: Env, token: Address) -> i128 {
+        Self::min_transfers(env).get(token).unwrap_or(0)
+    }
 
-        let token_client = match immutables.token {
-            Some(ref token) => &token::Client::new(&env, token),
-            None => &lumens_client,
-        };
+    // Get the smallest amounts escrows may transfer, by token
+    pub fn min_transfers(env: Env) -> Map<Address, i128> {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "min_transfers"))
+            .unwrap_or(Map::new(&env))
+    }
 
-        // Transfer tokens back
-        token_client.transfer(&sender, payee, &resolves.amount);
+    // Set the tokens safety deposits may be made in, empty allows any
+    pub fn set_deposit_tokens(env: Env, deposit_tokens: Vec<Address>) {
+        Self::require_admin(&env);
 
-        // Transfer safety deposit to caller
-        lumens_client.transfer(&sender, &caller, &immutables.safety_deposit);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "deposit_tokens"), &deposit_tokens);
+    }
 
-        // Update state
+    // Get the tokens safety deposits may be made in
+    pub fn deposit_tokens(env: Env) -> Vec<Address> {
         env.storage()
             .instance()
-            .set(&Symbol::new(&env, "state"), &EscrowState::Cancelled);
+            .get(&Symbol::new(&env, "deposit_tokens"))
+            .unwrap_or(vec![&env])
+    }
 
-        // Emit event
-        env.events().publish((Symbol::new(&env, "cancel"),), ());
+    // Set the number of parts an order can be split into
+    pub fn set_max_parts(env: Env, max_parts: u32) {
+        Self::require_admin(&env);
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "max_parts"), &max_parts);
+    }
+
+    // Get the number of parts an order can be split into
+    pub fn max_parts(env: Env) -> u32 {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "max_parts"))
+            .unwrap_or(DEFAULT_MAX_PARTS)
+    }
+
+    // Get the cumulative principal withdrawn and refunded by escrows in a token
+    pub fn volume_of(env: Env, token: Address) -> (i128, i128) {
+        env.storage()
+            .persistent()
+            .get(&(Symbol::new(&env, "volume"), token))
+            .unwrap_or((0, 0))
+    }
+
+    // Get the most recent escrows created for a taker, oldest first
+    pub fn escrows_of_taker(env: Env, taker: Address) -> Vec<Address> {
+        let key = (Symbol::new(&env, "taker_escrows"), taker);
+        match env.storage().persistent().get(&key) {
+            Some(escrows) => {
+                Self::bump_persistent(&env, &key);
+                escrows
+            }
+            None => Vec::new(&env),
+        }
+    }
+
+    // Get the states of several escrows in the order given
+    pub fn states_of(env: Env, escrows: Vec<Address>) -> Vec<EscrowState> {
+        if escrows.len() > MAX_BATCH_QUERY {
+            panic_with_error!(&env, EscrowError::BatchTooLarge);
+        }
+
+        let mut states = Vec::new(&env);
+        for escrow in escrows.iter() {
+            states.push_back(EscrowClient::new(&env, &escrow).get_state());
+        }
+        states
+    }
+
+    // Get the state, phase and amount of several escrows in the order given
+    pub fn overview(env: Env, escrows: Vec<Address>) -> Vec<EscrowOverview> {
+        if escrows.len() > MAX_BATCH_QUERY {
+            panic_with_error!(&env, EscrowError::BatchTooLarge);
+        }
+
+        let mut overviews = Vec::new(&env);
+        for escrow in escrows.iter() {
+            let client = EscrowClient::new(&env, &escrow);
+            overviews.push_back(EscrowOverview {
+                state: client.get_state(),
+                phase: client.phase(),
+                amount: client.get_resolves().amount,
+            });
+        }
+        overviews
     }
 
-    // Get escrow immutables
-    pub fn get_immutables(env: Env) -> EscrowImmutables {
+    // Get the number of escrows this factory has created
+    pub fn escrow_count(env: Env) -> u32 {
         env.storage()
             .instance()
-            .get(&Symbol::new(&env, "immutables"))
+            .get(&Symbol::new(&env, "escrow_count"))
+            .unwrap_or(0)
+    }
+
+    // Check an address is an escrow this factory deployed, rather than a lookalike
+    pub fn is_my_escrow(env: Env, escrow: Address) -> bool {
+        env.storage()
+            .persistent()
+            .has(&(Symbol::new(&env, "created"), escrow))
+    }
+
+    // Get the first page of escrows anyone can withdraw or cancel now
+    pub fn public_actionable(env: Env) -> Vec<Address> {
+        Self::public_actionable_page(env, 0, MAX_BATCH_QUERY)
+    }
+
+    // Get the escrows anyone can withdraw or cancel now among those created
+    // from index start on, checking at most limit escrows
+    pub fn public_actionable_page(env: Env, start: u32, limit: u32) -> Vec<Address> {
+        if limit > MAX_BATCH_QUERY {
+            panic_with_error!(&env, EscrowError::BatchTooLarge);
+        }
+
+        let end = Self::escrow_count(env.clone()).min(start.saturating_add(limit));
+        let mut escrows = Vec::new(&env);
+        for index in start..end {
+            let key = (Symbol::new(&env, "escrow"), index);
+            let escrow: Address = env.storage().persistent().get(&key).unwrap();
+            Self::bump_persistent(&env, &key);
+            if Self::publicly_actionable(&env, &escrow) {
+                escrows.push_back(escrow);
+            }
+        }
+        escrows
+    }
+
+    // Get the whole factory configuration
+    pub fn config(env: Env) -> FactoryConfig {
+        let rebate = Self::rebate(env.clone());
+        FactoryConfig {
+            admin: Self::admin(env.clone()),
+            dispute_window: Self::dispute_window(env.clone()),
+            max_parts: Self::max_parts(env.clone()),
+            min_amount: Self::min_amount(env.clone()),
+            clock_source: Self::clock_source(env.clone()),
+            public_cooldown: Self::public_cooldown(env.clone()),
+            ttl_bump: Self::ttl_bump(env.clone()),
+            keeper_reward: Self::keeper_reward(env.clone()),
+            topic_prefix: Self::topic_prefix(env.clone()),
+            escrow_wasm_hash: Self::escrow_wasm_hash(env.clone()),
+            amount_validity: Self::amount_validity(env.clone()),
+            reward_ramp: Self::reward_ramp(env.clone()),
+            maker_auth_cancel: Self::maker_auth_cancel(env.clone()),
+            max_future: Self::max_future(env.clone()),
+            cancel_oracle: Self::cancel_oracle(env.clone()),
+            no_safety_deposit: Self::no_safety_deposit(env.clone()),
+            protocol_fee: Self::protocol_fee(env.clone()),
+            guardian: Self::guardian(env.clone()),
+            require_eoa: Self::require_eoa(env.clone()),
+            secret_registry: Self::secret_registry(env.clone()),
+            deposit_tokens: Self::deposit_tokens(env.clone()),
+            min_transfers: Self::min_transfers(env.clone()),
+            pending_admin: Self::pending_admin(env.clone()),
+            rebate_token: rebate.as_ref().map(|rebate| rebate.token.clone()),
+            rebate_amount: rebate.as_ref().map_or(0, |rebate| rebate.amount),
+            rebate_prompt_window: rebate.as_ref().map_or(0, |rebate| rebate.prompt_window),
+            rebate_pool: match rebate {
+                Some(rebate) => Self::rebate_pool(env, rebate.token),
+                None => 0,
+            },
+        }
+    }
+
+    // Get the prefix of the event topics of this deployment and its escrows
+    pub fn topic_prefix(env: Env) -> Symbol {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "topic_prefix"))
             .unwrap()
     }
 
-    // Get escrow resolves
-    pub fn get_resolves(env: Env) -> EscrowResolves {
+    // Get the hash of the wasm escrows are deployed from
+    pub fn escrow_wasm_hash(env: Env) -> BytesN<32> {
         env.storage()
             .instance()
-            .get(&Symbol::new(&env, "resolves"))
+            .get(&Symbol::new(&env, "escrow_wasm_hash"))
             .unwrap()
     }
 
-    // Get escrow state
-    pub fn get_state(env: Env) -> EscrowState {
+    // Get factory admin
+    pub fn admin(env: Env) -> Address {
         env.storage()
             .instance()
-            .get(&Symbol::new(&env, "state"))
+            .get(&Symbol::new(&env, "admin"))
             .unwrap()
     }
+
+    // Propose a new admin, who takes over only once accepting, so a mistyped
+    // address can't lock the factory. A later proposal replaces a pending one
+    pub fn transfer_admin(env: Env, current_admin: Address, new_admin: Address) {
+        current_admin.require_auth();
+        if current_admin != Self::admin(env.clone()) {
+            panic_with_error!(&env, EscrowError::Unauthorized);
+        }
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "pending_admin"), &new_admin);
+    }
+
+    // Accept a pending admin transfer as the proposed admin
+    pub fn accept_admin(env: Env, new_admin: Address) {
+        new_admin.require_auth();
+        if Self::pending_admin(env.clone()) != Some(new_admin.clone()) {
+            panic_with_error!(&env, EscrowError::Unauthorized);
+        }
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "admin"), &new_admin);
+        env.storage()
+            .instance()
+            .remove(&Symbol::new(&env, "pending_admin"));
+    }
+
+    // Get the admin proposed by the current one, if not yet accepted
+    pub fn pending_admin(env: Env) -> Option<Address> {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "pending_admin"))
+    }
+
+    // Get factory logic version
+    pub fn version(_env: Env) -> u32 {
+        VERSION
+    }
+}
+
+impl EscrowFactory {
+    fn require_admin(env: &Env) {
+        Self::admin(env.clone()).require_auth();
+    }
+
+    // Deploy the escrow wasm at the address predicted from the salt, failing should the
+    // host place it anywhere else as funds were already sent to the prediction
+    fn deploy_at(env: &Env, salt: BytesN<32>, address: &Address) {
+        let deployed = env
+            .deployer()
+            .with_current_contract(salt)
+            .deploy_v2(Self::escrow_wasm_hash(env.clone()), ());
+        if deployed != *address {
+            panic_with_error!(env, EscrowError::AddressMismatch);
+        }
+    }
+
+    // Amount of the calculation on the factory clock, failing creation on overflow
+    // rather than locking a wrapped amount
+    fn current_amount(env: &Env, amount: &AmountCalc) -> i128 {
+        amount
+            .checked_calc(Self::clock_source(env.clone()).now(env))
+            .unwrap_or_else(|| panic_with_error!(env, EscrowError::AmountOverflow))
+    }
+
+    // Whether anyone can withdraw or cancel an escrow now, evaluated here as the
+    // escrow can't call back into the factory while queried by it
+    fn publicly_actionable(env: &Env, escrow: &Address) -> bool {
+        let client = EscrowClient::new(env, escrow);
+        if !matches!(client.get_state(), EscrowState::Active) {
+            return false;
+        }
+        let immutables = client.get_immutables();
+        let resolves = client.get_resolves();
+
+        let timestamp = env.ledger().timestamp();
+        let revealed = Self::secrets_revealed(env, &immutables, &resolves);
+        let withdrawal_end = resolves
+            .timestamp
+            .saturating_add(immutables.timelocks.cancellation);
+        let has_started = |delay: u64| matches!(resolves.timestamp.checked_add(delay), Some(start) if timestamp >= start);
+
+        // Public withdrawal, until the taker's cancellation opens or for good once
+        // the secret is revealed
+        let withdrawable = has_started(immutables.timelocks.public_withdrawal)
+            && (timestamp < withdrawal_end || revealed);
+        // Public cancellation, after withdrawal has closed and while the secret is unknown
+        let cancellable = has_started(immutables.timelocks.public_cancellation)
+            && timestamp >= withdrawal_end
+            && !revealed;
+        withdrawable || cancellable
+    }
+
+    // Whether every secret an escrow is locked by has been revealed, one revealed
+    // through a sibling sharing only the first hashlock leaving cancellation open
+    fn secrets_revealed(
+        env: &Env,
+        immutables: &EscrowImmutables,
+        resolves: &EscrowResolves,
+    ) -> bool {
+        Self::is_revealed(env.clone(), resolves.hashlock.clone())
+            && immutables
+                .second_hashlock
+                .clone()
+                .is_none_or(|hashlock| Self::is_revealed(env.clone(), hashlock))
+    }
+
+    // Require the caller to be an escrow this factory deployed, keeping its record
+    // alive as long as the escrow
+    fn require_escrow(env: &Env, escrow: &Address) {
+        escrow.require_auth();
+
+        if !Self::is_my_escrow(env.clone(), escrow.clone()) {
+            panic_with_error!(env, EscrowError::Unauthorized);
+        }
+        Self::bump_created(env, escrow);
+    }
+
+    // Extend the record of an escrow this factory deployed by the configured bump
+    fn bump_created(env: &Env, escrow: &Address) {
+        Self::bump_persistent(env, &(Symbol::new(env, "created"), escrow.clone()));
+    }
+
+    // Extend a persistent entry the factory keeps by the configured bump, so the
+    // indexes views read don't get archived under them
+    fn bump_persistent<K: IntoVal<Env, Val>>(env: &Env, key: &K) {
+        let ttl_bump = Self::ttl_bump(env.clone());
+        env.storage()
+            .persistent()
+            .extend_ttl(key, ttl_bump, ttl_bump);
+    }
+
+    // Index an escrow by taker, dropping the oldest entry once full, by creation order
+    // and by address, failing if its nonce was already used for the hashlock
+    fn index_escrow(env: &Env, address: &Address, taker: &Address) {
+        if Self::is_my_escrow(env.clone(), address.clone()) {
+            panic_with_error!(env, EscrowError::AlreadyTaken);
+        }
+
+        let mut escrows = Self::escrows_of_taker(env.clone(), taker.clone());
+        if escrows.len() >= MAX_ESCROWS_PER_TAKER {
+            escrows.pop_front();
+        }
+        escrows.push_back(address.clone());
+        let taker_key = (Symbol::new(env, "taker_escrows"), taker.clone());
+        env.storage().persistent().set(&taker_key, &escrows);
+        Self::bump_persistent(env, &taker_key);
+
+        let count = Self::escrow_count(env.clone());
+        let index_key = (Symbol::new(env, "escrow"), count);
+        env.storage().persistent().set(&index_key, address);
+        Self::bump_persistent(env, &index_key);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(env, "escrow_count"), &(count + 1));
+        env.storage()
+            .persistent()
+            .set(&(Symbol::new(env, "created"), address.clone()), &true);
+        Self::bump_created(env, address);
+    }
+
+    // Validate no party is a contract, when the factory requires accounts
+    fn validate_accounts(env: &Env, parties: Vec<Address>) {
+        if Self::require_eoa(env.clone()) && parties.iter().any(|party| is_contract_address(&party))
+        {
+            panic_with_error!(env, EscrowError::ContractParty);
+        }
+    }
+
+    // Deploy, fund and initialize an escrow for the order authorized by the maker
+    fn deploy_escrow(
+        env: &Env,
+        salt: BytesN<32>,
+        order: &EscrowImmutables,
+        immutables: EscrowImmutables,
+        taker: Address,
+        segment_index: Option<u32>,
+    ) -> Address {
+        let address = env
+            .deployer()
+            .with_current_contract(salt.clone())
+            .deployed_address();
+
+        // Require the funder's auth scoped to the exact terms it funds, in either
+        // direction: the maker agrees to the whole order, of which a segment is a part,
+        // the taker to the immutables of the escrow itself. A taker not funding still
+        // authorizes taking the order
+        let (sender, terms) = match order.direction {
+            EscrowDirection::Maker2Taker => {
+                taker.require_auth();
+                (&order.maker, order)
+            }
+            EscrowDirection::Taker2Maker => (&taker, &immutables),
+        };
+        sender.require_auth_for_args((terms.clone(),).into_val(env));
+
+        // Validate the parties are accounts, if required
+        let mut parties = vec![env, order.maker.clone(), taker.clone()];
+        if let Some(backup_taker) = &immutables.backup_taker {
+            parties.push_back(backup_taker.clone());
+        }
+        Self::validate_accounts(env, parties);
+
+        // Validate the amount calculation, hashlocks and windows
+        immutables.validate(env);
+
+        // Principal at the current auction level, a segment's being its flat amount
+        let amount = Self::current_amount(env, &immutables.amount);
+
+        // Validate no timelock ends further than now + max_future
+        let max_future = Self::max_future(env.clone());
+        let timelocks = &immutables.timelocks;
+        if max_future > 0 {
+            for delay in [
+                timelocks.withdrawal,
+                timelocks.public_withdrawal,
+                timelocks.cancellation,
+                timelocks.public_cancellation,
+            ] {
+                if delay > max_future {
+                    panic_with_error!(env, EscrowError::TimelockTooFar);
+                }
+            }
+        }
+
+        // Safety deposits pulled from the taker, none when the mechanism is disabled
+        let deposits = if Self::no_safety_deposit(env.clone()) {
+            vec![env]
+        } else {
+            immutables.safety_deposits(env, amount)
+        };
+
+        // Validate safety deposits flow from the taker to the escrow
+        for (_, deposit_amount) in deposits.iter() {
+            if deposit_amount < 0 {
+                panic_with_error!(env, EscrowError::InvalidImmutables);
+            }
+        }
+
+        // Validate each deposit token is listed once, so every deposit is accounted once
+        for (i, (deposit_token, _)) in deposits.iter().enumerate() {
+            for (other_token, _) in deposits.iter().skip(i + 1) {
+                if deposit_token == other_token {
+                    panic_with_error!(env, EscrowError::DuplicateDepositToken);
+                }
+            }
+        }
+
+        // Validate deposits are made in allowed tokens
+        let deposit_tokens = Self::deposit_tokens(env.clone());
+        if !deposit_tokens.is_empty() {
+            for (deposit_token, _) in deposits.iter() {
+                if !deposit_tokens.contains(&deposit_token) {
+                    panic_with_error!(env, EscrowError::DepositTokenNotAllowed);
+                }
+            }
+        }
+
+        // Validate the escrow locks something and is worth its fees
+        if amount <= 0 || amount < Self::min_amount(env.clone()) {
+            panic_with_error!(env, EscrowError::AmountTooSmall);
+        }
+
+        // Validate no transfer is dust, settlement folds any dust part of a split
+        // deposit or fee into the rest so withdraw and cancel don't hit a token panic.
+        // Zero deposits transfer nothing
+        if amount < Self::min_transfer(env.clone(), immutables.token.clone()) {
+            panic_with_error!(env, EscrowError::BelowMinTransfer);
+        }
+        for (deposit_token, deposit_amount) in deposits.iter() {
+            if deposit_amount > 0 && deposit_amount < Self::min_transfer(env.clone(), deposit_token)
+            {
+                panic_with_error!(env, EscrowError::BelowMinTransfer);
+            }
+        }
+
+        let timestamp = env.ledger().timestamp();
+
+        let token_client = token::Client::new(env, &immutables.token);
+
+        // Transfer tokens to escrow
+        token_client.transfer(sender, &address, &amount);
+
+        // Transfer safety deposits, a failing transfer reverts the whole creation,
+        // zero deposits needing none
+        for (deposit_token, deposit_amount) in deposits.iter().filter(|(_, amount)| *amount > 0) {
+            token::Client::new(env, &deposit_token).transfer(&taker, &address, &deposit_amount);
+        }
+
+        // Index escrow
+        Self::index_escrow(env, &address, &taker);
+
+        // Initialize escrow contracts
+        Self::deploy_at(env, salt, &address);
+        EscrowClient::new(env, &address).initialize(
+            &immutables,
+            &EscrowResolves {
+                factory: env.current_contract_address(),
+                taker,
+                amount,
+                priced_amount: amount,
+                safety_deposit: immutables.safety_deposit.amount(env, amount),
+                hashlock: immutables.hashlock.clone(),
+                timestamp,
+                withdrawal: immutables.timelocks.withdrawal,
+                dispute_window: Self::dispute_window(env.clone()),
+                order_hashlock: order.hashlock.clone(),
+                segment_index,
+                rebate: true,
+                ttl_bump: Self::ttl_bump(env.clone()),
+                keeper_reward: Self::keeper_reward(env.clone()),
+                topic_prefix: Self::topic_prefix(env.clone()),
+                amount_valid_until: match Self::amount_validity(env.clone()) {
+                    0 => None,
+                    validity => Some(timestamp.saturating_add(validity)),
+                },
+                reward_ramp: Self::reward_ramp(env.clone()),
+                maker_auth_cancel: Self::maker_auth_cancel(env.clone()),
+                cancel_oracle: Self::cancel_oracle(env.clone()),
+                no_safety_deposit: Self::no_safety_deposit(env.clone()),
+                protocol_fee: Self::protocol_fee(env.clone()),
+                secret_registry: Self::secret_registry(env.clone()),
+                clock_source: Self::clock_source(env.clone()),
+            },
+        );
+
+        // Keep the factory alive while it's in use
+        let ttl_bump = Self::ttl_bump(env.clone());
+        env.storage().instance().extend_ttl(ttl_bump, ttl_bump);
+
+        // Emit event with the amount the auction resolved to at fill time
+        env.events().publish(
+            (
+                Self::topic_prefix(env.clone()),
+                Symbol::new(env, "escrow_created"),
+                address.clone(),
+            ),
+            (
+                immutables.hashlock,
+                amount,
+                timestamp,
+                immutables.maker_receiver,
+            ),
+        );
+
+        address
+    }
 }
 
-// mod test;
+mod test;
@@ -1,27 +1,225 @@
 // test.rs
 #![cfg(test)]
+extern crate std;
 
-use rand::{Fill};
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU32, Ordering};
+use ed25519_dalek::{Signer, SigningKey};
+
+use std::rc::Rc;
 
 use soroban_sdk::{
-    testutils::{Address as _, Ledger}, token, Address, Bytes, Env
+    bytes, bytesn, xdr::{ContractDataDurability, FromXdr, LedgerKey, LedgerKeyContractData, ScErrorCode, ScErrorType, ScVal}, testutils::{storage::{Instance as _, Persistent as _}, Address as _, Events, Ledger, MockAuth, MockAuthInvoke}, token, vec, Address, Bytes, BytesN, Env, IntoVal, Map, String, Symbol, TryFromVal, Val, Vec, ConversionError, InvokeError
 };
 
-use crate::{
-    AmountCalc, DutchAuction, EscrowClient, EscrowDirection, EscrowError, EscrowFactory,
-    EscrowFactoryClient, EscrowImmutables, EscrowState, TimeLocks,
+use crate::interfaces::Immutables;
+use crate::libraries::{Stage, Timelocks};
+
+use crate::{EscrowFactory, EscrowFactoryClient};
+use escrow_interface::{
+    AmountCalc, CallerEconomics, CallerRole, ClockSource, DutchAuction, EscrowDirection, EscrowError,
+    EscrowImmutables, EscrowOverview, EscrowPhase, EscrowState, FactoryConfig, FeeRounding, HashAlgo, ProtocolFee, Rebate, SafetyDeposit,
+    TimeLocks, DEFAULT_MAX_PARTS, DEFAULT_TIMELOCKS, EscrowImmutablesBuilder, source_cancelled_message, ESCROW_TTL_EXTEND_TO, ESCROW_TTL_THRESHOLD, GUARDIAN_GRACE, MAX_BATCH_QUERY, MAX_ESCROWS_PER_TAKER, VERSION, derive_hashlock, is_contract_address, merkle_leaf, merkle_node,
 };
+use factory_escrow::{Escrow, EscrowClient};
 
 fn create_token_contract<'a>(e: &Env, admin: &Address) -> (token::StellarAssetClient<'a>, token::TokenClient<'a>) {
     let address = e.register_stellar_asset_contract_v2(admin.clone()).address();
     (token::StellarAssetClient::new(e, &address), token::TokenClient::new(e, &address))
 }
 
-fn create_escrow_factory_contract<'a>(e: &Env) -> EscrowFactoryClient<'a> {
-    let address = e.register(EscrowFactory, ());
-    EscrowFactoryClient::new(e, &address)
+// Escrow wasm the factory deploys, the empty wasm tests run natively
+fn upload_escrow_wasm(e: &Env) -> BytesN<32> {
+    e.deployer().upload_contract_wasm(Bytes::new(e))
+}
+
+fn create_escrow_factory_contract<'a>(e: &Env) -> TestFactory<'a> {
+    let address = e.register(EscrowFactory, (Address::generate(e), Symbol::new(e, "htlc"), upload_escrow_wasm(e)));
+    TestFactory::new(e, &address)
+}
+
+// The host only runs the empty wasm for natively bound contracts, so bind the escrow
+// to an address the factory is about to deploy to and drop the instance again for
+// the deployment to create it. Addresses already holding an escrow are left as is
+fn bind_escrow(e: &Env, address: &Address) {
+    let key = Rc::new(LedgerKey::ContractData(LedgerKeyContractData {
+        contract: address.into(),
+        key: ScVal::LedgerKeyContractInstance,
+        durability: ContractDataDurability::Persistent,
+    }));
+    let budget = e.host().budget_cloned();
+    if e.host().with_mut_storage(|storage| storage.has(&key, &budget)).unwrap() {
+        return;
+    }
+
+    e.register_at(address, Escrow, ());
+    e.host().with_mut_storage(|storage| storage.del(&key, &budget)).unwrap();
+}
+
+type TryCreate = Result<Result<Address, ConversionError>, Result<soroban_sdk::Error, InvokeError>>;
+
+// Factory client binding the escrow at the predicted address before each creation
+struct TestFactory<'a> {
+    client: EscrowFactoryClient<'a>,
+}
+
+impl<'a> TestFactory<'a> {
+    fn new(e: &Env, address: &Address) -> Self {
+        TestFactory { client: EscrowFactoryClient::new(e, address) }
+    }
+
+    fn bind(&self, hashlock: &BytesN<32>, nonce: u64) {
+        bind_escrow(&self.env, &self.predict_address(hashlock, &nonce));
+    }
+
+    fn create_escrow(&self, immutables: &EscrowImmutables, nonce: &u64, taker: &Address) -> Address {
+        self.bind(&immutables.hashlock, *nonce);
+        self.client.create_escrow(immutables, nonce, taker)
+    }
+
+    fn try_create_escrow(&self, immutables: &EscrowImmutables, nonce: &u64, taker: &Address) -> TryCreate {
+        self.bind(&immutables.hashlock, *nonce);
+        self.client.try_create_escrow(immutables, nonce, taker)
+    }
+
+    fn create_dst_escrow(&self, immutables: &EscrowImmutables, nonce: &u64, taker: &Address, src_cancellation_timestamp: &u64) -> Address {
+        self.bind(&immutables.hashlock, *nonce);
+        self.client.create_dst_escrow(immutables, nonce, taker, src_cancellation_timestamp)
+    }
+
+    fn try_create_dst_escrow(&self, immutables: &EscrowImmutables, nonce: &u64, taker: &Address, src_cancellation_timestamp: &u64) -> TryCreate {
+        self.bind(&immutables.hashlock, *nonce);
+        self.client.try_create_dst_escrow(immutables, nonce, taker, src_cancellation_timestamp)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn create_escrow_for_segment(&self, immutables: &EscrowImmutables, merkle_proof: &Vec<BytesN<32>>, index: &u32, segment_hashlock: &BytesN<32>, segment_amount: &i128, taker: &Address) -> Address {
+        bind_escrow(&self.env, &self.predict_segment_address(&immutables.hashlock, index));
+        self.client.create_escrow_for_segment(immutables, merkle_proof, index, segment_hashlock, segment_amount, taker)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn try_create_escrow_for_segment(&self, immutables: &EscrowImmutables, merkle_proof: &Vec<BytesN<32>>, index: &u32, segment_hashlock: &BytesN<32>, segment_amount: &i128, taker: &Address) -> TryCreate {
+        bind_escrow(&self.env, &self.predict_segment_address(&immutables.hashlock, index));
+        self.client.try_create_escrow_for_segment(immutables, merkle_proof, index, segment_hashlock, segment_amount, taker)
+    }
+}
+
+impl<'a> Deref for TestFactory<'a> {
+    type Target = EscrowFactoryClient<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
+// Factory, principal and safety deposit tokens, and the two parties most tests start from
+struct Setup<'a> {
+    e: Env,
+    factory: TestFactory<'a>,
+    token_admin: Address,
+    token_sac: token::StellarAssetClient<'a>,
+    token: token::TokenClient<'a>,
+    safety_token_sac: token::StellarAssetClient<'a>,
+    safety_token: token::TokenClient<'a>,
+    maker: Address,
+    taker: Address,
+}
+
+fn setup<'a>() -> Setup<'a> {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let factory = create_escrow_factory_contract(&e);
+    let token_admin = Address::generate(&e);
+    let (token_sac, token) = create_token_contract(&e, &token_admin);
+    let (safety_token_sac, safety_token) = create_token_contract(&e, &token_admin);
+
+    let maker = Address::generate(&e);
+    let taker = Address::generate(&e);
+
+    Setup { e, factory, token_admin, token_sac, token, safety_token_sac, safety_token, maker, taker }
+}
+
+// Token whose transfers out of an armed escrow try to cancel a target escrow, the
+// armed one itself or a sibling, mid-settlement
+mod reentrant_token {
+    use soroban_sdk::{contract, contractimpl, Address, Env, Symbol};
+
+    use factory_escrow::EscrowClient;
+
+    #[contract]
+    pub struct ReentrantToken;
+
+    #[contractimpl]
+    impl ReentrantToken {
+        pub fn arm(env: Env, escrow: Address, target: Address) {
+            env.storage().instance().set(&Symbol::new(&env, "armed"), &(escrow, target));
+        }
+
+        pub fn balance(_env: Env, _id: Address) -> i128 {
+            i128::MAX
+        }
+
+        pub fn transfer(env: Env, from: Address, _to: Address, _amount: i128) {
+            let armed: Option<(Address, Address)> = env.storage().instance().get(&Symbol::new(&env, "armed"));
+            if let Some((escrow, target)) = armed {
+                if escrow == from {
+                    EscrowClient::new(&env, &target).cancel(&env.current_contract_address());
+                }
+            }
+        }
+    }
+}
+
+// Token whose transfers out of a frozen balance fail, as a frozen asset balance does
+mod freezable_token {
+    use soroban_sdk::{contract, contractimpl, Address, Env};
+
+    #[contract]
+    pub struct FreezableToken;
+
+    #[contractimpl]
+    impl FreezableToken {
+        pub fn freeze(env: Env, id: Address, frozen: bool) {
+            env.storage().instance().set(&id, &frozen);
+        }
+
+        pub fn balance(_env: Env, _id: Address) -> i128 {
+            i128::MAX
+        }
+
+        pub fn transfer(env: Env, from: Address, _to: Address, _amount: i128) {
+            if env.storage().instance().get(&from).unwrap_or(false) {
+                panic!("balance frozen");
+            }
+        }
+    }
+}
+
+// Secret registry recording the secrets pushed to it by hashlock
+mod mock_secret_registry {
+    use soroban_sdk::{contract, contractimpl, Bytes, BytesN, Env};
+
+    #[contract]
+    pub struct MockSecretRegistry;
+
+    #[contractimpl]
+    impl MockSecretRegistry {
+        pub fn push_secret(env: Env, hashlock: BytesN<32>, secret: Bytes) {
+            env.storage().instance().set(&hashlock, &secret);
+        }
+
+        pub fn secret_of(env: Env, hashlock: BytesN<32>) -> Option<Bytes> {
+            env.storage().instance().get(&hashlock)
+        }
+    }
 }
 
+use freezable_token::{FreezableToken, FreezableTokenClient};
+use mock_secret_registry::{MockSecretRegistry, MockSecretRegistryClient};
+use reentrant_token::{ReentrantToken, ReentrantTokenClient};
+
 // fn generate_hashlock(e: &Env) -> BytesN<32> {
 //     let mut arr = [0u8; 32];
 //     e.prng().fill(&mut arr);
@@ -29,9 +227,36 @@ fn create_escrow_factory_contract<'a>(e: &Env) -> EscrowFactoryClient<'a> {
 // }
 
 fn generate_secret(e: &Env) -> Bytes {
-    let mut arr = [0u8; 32];
-    arr.fill(&mut rand::rng());
-    Bytes::from_slice(e, &arr)
+    static NONCE: AtomicU32 = AtomicU32::new(0);
+    let nonce = NONCE.fetch_add(1, Ordering::Relaxed);
+    e.crypto().sha256(&Bytes::from_array(e, &nonce.to_be_bytes())).into()
+}
+
+// Immutables of a Maker2Taker escrow locking 500 of the token against a 50 safety
+// deposit under the usual test timelocks, for tests to override what they exercise
+fn base_immutables(e: &Env, hashlock: BytesN<32>, maker: &Address, token: &Address, safety_token: &Address) -> EscrowImmutables {
+    EscrowImmutables {
+        hashlock,
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
+        direction: EscrowDirection::Maker2Taker,
+        maker: maker.clone(),
+        refund_address_hint: Bytes::new(e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
+        token: token.clone(),
+        amount: AmountCalc::Flat(500),
+        safety_deposit_token: safety_token.clone(),
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![e],
+        timelocks: TimeLocks {
+            withdrawal: 1000,
+            public_withdrawal: 2000,
+            cancellation: 3000,
+            public_cancellation: 4000,
+        },
+    }
 }
 
 fn jump_time(e: &Env, gap: u64) {
@@ -59,12 +284,19 @@ fn test_create_escrow_maker_to_taker_flat_amount() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Maker2Taker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Flat(500),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -73,7 +305,7 @@ fn test_create_escrow_maker_to_taker_flat_amount() {
         },
     };
 
-    let escrow_address = factory.create_escrow(&immutables, &taker);
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
     let escrow = EscrowClient::new(&e, &escrow_address);
 
     // Check initial state
@@ -121,12 +353,19 @@ fn test_create_escrow_taker_to_maker_linear_amount() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Taker2Maker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Linear(dutch_auction),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -135,7 +374,7 @@ fn test_create_escrow_taker_to_maker_linear_amount() {
         },
     };
 
-    let escrow_address = factory.create_escrow(&immutables, &taker);
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
     let escrow = EscrowClient::new(&e, &escrow_address);
 
     // Check initial state
@@ -174,12 +413,19 @@ fn test_withdraw_with_correct_secret() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Maker2Taker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Flat(500),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -188,7 +434,7 @@ fn test_withdraw_with_correct_secret() {
         },
     };
 
-    let escrow_address = factory.create_escrow(&immutables, &taker);
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
     let escrow = EscrowClient::new(&e, &escrow_address);
 
     // Advance time past withdrawal timelock
@@ -207,6 +453,168 @@ fn test_withdraw_with_correct_secret() {
     assert_eq!(safety_token.balance(&escrow_address), 0);
 }
 
+#[test]
+fn test_deposit_recipient() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let caller = Address::generate(&e);
+    let secret = generate_secret(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    assert_eq!(escrow.deposit_recipient(), None);
+
+    // A public caller settles the escrow and takes the deposit
+    jump_time(&e, 2001);
+    escrow.withdraw(&secret, &caller);
+    assert_eq!(escrow.deposit_recipient(), Some(caller.clone()));
+    assert_eq!(safety_token.balance(&caller), 50);
+}
+
+#[test]
+fn test_withdraw_pushes_secret_to_registry() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let registry = MockSecretRegistryClient::new(&e, &e.register(MockSecretRegistry, ()));
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret).to_bytes();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    factory.set_secret_registry(&registry.address);
+
+    let immutables = base_immutables(&e, hashlock.clone(), &maker, &token.address, &safety_token.address);
+
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    assert_eq!(registry.secret_of(&hashlock), None);
+
+    // Withdrawal pushes the secret under its hashlock
+    jump_time(&e, 1001);
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(registry.secret_of(&hashlock), Some(secret));
+}
+
+#[test]
+fn test_withdraw_deposit_in_principal_token() {
+    let Setup { e, factory, token_sac, token, maker, taker, .. } = setup();
+
+    let caller = Address::generate(&e);
+
+    // Mint tokens, the safety deposit is paid in the principal token
+    token_sac.mint(&maker, &1000);
+    token_sac.mint(&taker, &100);
+
+    let immutables = |hashlock| base_immutables(&e, hashlock, &maker, &token.address, &token.address);
+
+    let private_secret = generate_secret(&e);
+    let public_secret = generate_secret(&e);
+    let private = factory.create_escrow(&immutables(e.crypto().sha256(&private_secret).to_bytes()), &0, &taker);
+    let public = factory.create_escrow(&immutables(e.crypto().sha256(&public_secret).to_bytes()), &0, &taker);
+    assert_eq!(token.balance(&taker), 0);
+
+    // The taker gets principal and deposit together
+    jump_time(&e, 1001);
+    EscrowClient::new(&e, &private).withdraw(&private_secret, &taker);
+    assert_eq!(token.balance(&taker), 550);
+    assert_eq!(token.balance(&private), 0);
+
+    // A public caller gets the deposit and the taker the principal
+    jump_time(&e, 1000);
+    EscrowClient::new(&e, &public).withdraw(&public_secret, &caller);
+    assert_eq!(token.balance(&caller), 50);
+    assert_eq!(token.balance(&taker), 1050);
+    assert_eq!(token.balance(&public), 0);
+}
+
+#[test]
+fn test_backup_taker_withdraw() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let backup = Address::generate(&e);
+    let caller = Address::generate(&e);
+    let secret = generate_secret(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let mut immutables = EscrowImmutables {
+        backup_taker: Some(backup.clone()),
+        backup_withdrawal: 2000,
+        ..base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // The backup window must open after the taker's and before the public one
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidBackupWithdrawal.into())));
+    immutables.backup_withdrawal = 1000;
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidBackupWithdrawal.into())));
+
+    immutables.backup_withdrawal = 1500;
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+
+    // The taker misses its window, the backup waits for its own
+    jump_time(&e, 1200);
+    let error = escrow.try_withdraw(&secret, &backup);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    // The backup withdraws before the public window opens
+    jump_time(&e, 400);
+    let error = escrow.try_withdraw(&secret, &caller);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    // Claiming to be the backup without its auth doesn't open its window
+    e.set_auths(&[]);
+    let error = escrow.try_withdraw(&secret, &backup);
+    assert!(error.is_err());
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+    e.mock_all_auths();
+
+    escrow.withdraw(&secret, &backup);
+    assert_eq!(e.auths()[0].0, backup);
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&taker), 500);
+    assert_eq!(safety_token.balance(&backup), 50);
+}
+
+#[test]
+fn test_withdraw_fixed() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = BytesN::from_array(&e, &[9; 32]);
+    let hashlock = e.crypto().sha256(&secret.clone().into());
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    let error = escrow.try_withdraw_fixed(&BytesN::from_array(&e, &[8; 32]), &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidSecret.into())));
+
+    escrow.withdraw_fixed(&secret, &taker);
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    assert_eq!(escrow.revealed_secret(), Some(Bytes::from(secret)));
+    assert_eq!(token.balance(&taker), 500);
+    assert_eq!(safety_token.balance(&taker), 100);
+}
+
 #[test]
 fn test_withdraw_with_incorrect_secret() {
     let e = Env::default();
@@ -229,12 +637,19 @@ fn test_withdraw_with_incorrect_secret() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Maker2Taker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Flat(500),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -243,7 +658,7 @@ fn test_withdraw_with_incorrect_secret() {
         },
     };
 
-    let escrow_address = factory.create_escrow(&immutables, &taker);
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
     let escrow = EscrowClient::new(&e, &escrow_address);
 
     // Advance time past withdrawal timelock
@@ -257,6 +672,36 @@ fn test_withdraw_with_incorrect_secret() {
     assert_eq!(escrow.get_state(), EscrowState::Active);
 }
 
+#[test]
+fn test_secret_format() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Hashes fine but is longer than any withdrawal accepts
+    let long_secret = Bytes::from_array(&e, &[1; 65]);
+    let hashlock = e.crypto().sha256(&long_secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    assert!(escrow.is_valid_secret_format(&generate_secret(&e)));
+    assert!(escrow.is_valid_secret_format(&Bytes::from_array(&e, &[1; 64])));
+    assert!(!escrow.is_valid_secret_format(&long_secret));
+    assert!(!escrow.is_valid_secret_format(&Bytes::new(&e)));
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    let error = escrow.try_withdraw(&long_secret, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidSecret.into())));
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+}
+
 #[test]
 fn test_withdraw_too_early() {
     let e = Env::default();
@@ -278,12 +723,19 @@ fn test_withdraw_too_early() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Maker2Taker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Flat(500),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -292,7 +744,7 @@ fn test_withdraw_too_early() {
         },
     };
 
-    let escrow_address = factory.create_escrow(&immutables, &taker);
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
     let escrow = EscrowClient::new(&e, &escrow_address);
 
     // Try to withdraw before timelock
@@ -324,12 +776,19 @@ fn test_cancel_by_taker() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Maker2Taker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Flat(500),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -338,7 +797,7 @@ fn test_cancel_by_taker() {
         },
     };
 
-    let escrow_address = factory.create_escrow(&immutables, &taker);
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
     let escrow = EscrowClient::new(&e, &escrow_address);
 
     // Advance time past cancellation timelock
@@ -379,12 +838,19 @@ fn test_cancel_by_public_too_early() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Maker2Taker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Flat(500),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -393,7 +859,7 @@ fn test_cancel_by_public_too_early() {
         },
     };
 
-    let escrow_address = factory.create_escrow(&immutables, &taker);
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
     let escrow = EscrowClient::new(&e, &escrow_address);
 
     // Try to cancel by public before timelock
@@ -425,12 +891,19 @@ fn test_double_withdraw() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Maker2Taker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Flat(500),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -439,7 +912,7 @@ fn test_double_withdraw() {
         },
     };
 
-    let escrow_address = factory.create_escrow(&immutables, &taker);
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
     let escrow = EscrowClient::new(&e, &escrow_address);
 
     // Advance time past withdrawal timelock
@@ -474,12 +947,19 @@ fn test_withdraw_after_cancel() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Maker2Taker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Flat(500),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -488,7 +968,7 @@ fn test_withdraw_after_cancel() {
         },
     };
 
-    let escrow_address = factory.create_escrow(&immutables, &taker);
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
     let escrow = EscrowClient::new(&e, &escrow_address);
 
     // Advance time past cancellation timelock
@@ -531,6 +1011,39 @@ fn test_dutch_auction_amount_calculation() {
     assert_eq!(calc.calc(2500), 500);
 }
 
+#[test]
+fn test_create_escrow_amount_overflow() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    // Interpolating midway multiplies the amounts by the remaining duration
+    let current_time = e.ledger().timestamp();
+    let dutch_auction = DutchAuction {
+        start_time: current_time,
+        stop_time: current_time + 1000,
+        start_amount: i128::MAX,
+        stop_amount: 0,
+    };
+    assert_eq!(
+        AmountCalc::Linear(dutch_auction.clone()).checked_calc(current_time + 500),
+        None
+    );
+
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Linear(dutch_auction),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    jump_time(&e, 500);
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::AmountOverflow.into())));
+    let error = factory.try_required_funding(&immutables);
+    assert_eq!(error.err(), Some(Ok(EscrowError::AmountOverflow.into())));
+}
+
 #[test]
 fn test_create_escrow_unauthorized_taker() {
     let e = Env::default();
@@ -547,12 +1060,19 @@ fn test_create_escrow_unauthorized_taker() {
 
     let immutables = EscrowImmutables {
         hashlock: hashlock.to_bytes(),
+        second_hashlock: None,
+        hash_algo: HashAlgo::Sha256,
         direction: EscrowDirection::Maker2Taker,
         maker: maker.clone(),
+        refund_address_hint: Bytes::new(&e),
+        maker_receiver: None,
+        backup_taker: None,
+        backup_withdrawal: 0,
         token: token.address.clone(),
         amount: AmountCalc::Flat(500),
         safety_deposit_token: safety_token.address.clone(),
-        safety_deposit_amount: 50,
+        safety_deposit: SafetyDeposit::Flat(50),
+        extra_safety_deposits: vec![&e],
         timelocks: TimeLocks {
             withdrawal: 1000,
             public_withdrawal: 2000,
@@ -562,6 +1082,4230 @@ fn test_create_escrow_unauthorized_taker() {
     };
 
     // Try to create escrow with unauthorized taker
-    let error = factory.try_create_escrow(&immutables, &unauthorized_taker);
+    let error = factory.try_create_escrow(&immutables, &0, &unauthorized_taker);
     assert!(error.is_err());
-}
\ No newline at end of file
+}
+#[test]
+fn test_withdraw_reveals_secret_to_factory() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    assert!(!factory.is_revealed(&hashlock.to_bytes()));
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    escrow.withdraw(&secret, &taker);
+
+    // Secret is now known to the factory
+    assert!(factory.is_revealed(&hashlock.to_bytes()));
+}
+
+#[test]
+fn test_revealed_secret_blocks_cancellation() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, .. } = setup();
+
+    let taker_a = Address::generate(&e);
+    let taker_b = Address::generate(&e);
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker_a, &100);
+
+    // Two escrows locked by the same hashlock
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Flat(1000),
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+    let escrow_a = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker_a));
+    factory.bind(&immutables.hashlock, 1);
+    let escrow_b = EscrowClient::new(&e, &escrow_a.split_escrow(&400, &taker_b, &1));
+
+    // Only an escrow of the factory can reveal a secret
+    let error = factory.try_reveal(&Address::generate(&e), &secret, &HashAlgo::Sha256);
+    assert_eq!(error.err(), Some(Ok(EscrowError::Unauthorized.into())));
+    assert!(!factory.is_revealed(&hashlock.to_bytes()));
+
+    // Withdrawing one escrow reveals the secret to the factory
+    jump_time(&e, 1001);
+    escrow_a.withdraw(&secret, &taker_a);
+    assert!(factory.is_revealed(&hashlock.to_bytes()));
+
+    // Advance time past cancellation timelock
+    jump_time(&e, 2000);
+
+    // Cancellation of the other is blocked
+    let error = escrow_b.try_cancel(&taker_b);
+    assert_eq!(error.err(), Some(Ok(EscrowError::SecretRevealed.into())));
+    assert_eq!(escrow_b.get_state(), EscrowState::Active);
+    assert_eq!(factory.public_actionable(), vec![&e, escrow_b.address.clone()]);
+
+    // Withdrawal stays open past the cancellation timelock, so the funds aren't stuck
+    escrow_b.withdraw(&secret, &taker_b);
+    assert_eq!(escrow_b.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&taker_a), 600);
+    assert_eq!(token.balance(&taker_b), 400);
+    assert_eq!(token.balance(&escrow_b.address), 0);
+}
+
+#[test]
+fn test_windows_overflowing_u64_never_open() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        timelocks: TimeLocks {
+            withdrawal: 1000,
+            public_withdrawal: 2000,
+            cancellation: u64::MAX,
+            public_cancellation: u64::MAX,
+        },
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Create the escrow close to the end of time
+    e.ledger().set_timestamp(u64::MAX - 500);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    jump_time(&e, 500);
+
+    // Withdrawal start overflows and is treated as never reached
+    let error = escrow.try_withdraw(&secret, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    // Same for cancellation
+    let error = escrow.try_cancel(&taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+}
+
+#[test]
+fn test_version() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    assert_eq!(VERSION, 2);
+    assert_eq!(factory.version(), VERSION);
+    assert_eq!(escrow.version(), VERSION);
+}
+
+#[test]
+fn test_protocol_fee_rounding() {
+    let e = Env::default();
+
+    let down = ProtocolFee {
+        bps: 30,
+        rounding: FeeRounding::RoundDown,
+    };
+    let up = ProtocolFee {
+        bps: 30,
+        rounding: FeeRounding::RoundUp,
+    };
+
+    // 1001 * 0.3% = 3.003
+    assert_eq!(down.calc(&e, 1001), 3);
+    assert_eq!(up.calc(&e, 1001), 4);
+
+    // Exact results are not affected by rounding
+    assert_eq!(down.calc(&e, 1000), 3);
+    assert_eq!(up.calc(&e, 1000), 3);
+
+    // Zero stays zero
+    assert_eq!(down.calc(&e, 0), 0);
+    assert_eq!(up.calc(&e, 0), 0);
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #18)")]
+fn test_protocol_fee_overflow() {
+    let e = Env::default();
+
+    ProtocolFee {
+        bps: 30,
+        rounding: FeeRounding::RoundDown,
+    }
+    .calc(&e, i128::MAX);
+}
+
+#[test]
+fn test_protocol_fees_collected_and_withdrawn() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let treasury = Address::generate(&e);
+
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    // 1% of the principal goes to the protocol
+    factory.set_protocol_fee(&ProtocolFee { bps: 100, rounding: FeeRounding::RoundDown });
+
+    for amount in [500, 300] {
+        let secret = generate_secret(&e);
+        let immutables = EscrowImmutables {
+            amount: AmountCalc::Flat(amount),
+            ..base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address)
+        };
+
+        let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+        jump_time(&e, 1001);
+        EscrowClient::new(&e, &escrow_address).withdraw(&secret, &taker);
+        assert_eq!(token.balance(&escrow_address), 0);
+    }
+
+    // Fees of 5 and 3 accrue, the taker getting the rest
+    assert_eq!(token.balance(&taker), 792);
+    assert_eq!(factory.collected_fees(&token.address), 8);
+    assert_eq!(factory.collected_fees(&safety_token.address), 0);
+
+    // The admin withdraws the total, resetting the accrual
+    assert_eq!(factory.withdraw_fees(&token.address, &treasury), 8);
+    assert_eq!(token.balance(&treasury), 8);
+    assert_eq!(token.balance(&factory.address), 0);
+    assert_eq!(factory.collected_fees(&token.address), 0);
+
+    // Fees above the whole principal are rejected
+    let error = factory.try_set_protocol_fee(&ProtocolFee { bps: 10_001, rounding: FeeRounding::RoundDown });
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+}
+
+#[test]
+fn test_create_dst_escrow_cancellation_before_src() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&taker, &3000);
+    safety_token_sac.mint(&taker, &300);
+
+    e.ledger().set_timestamp(10_000);
+
+    let dst_immutables = |hashlock| EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        ..base_immutables(&e, hashlock, &maker, &token.address, &safety_token.address)
+    };
+
+    // Destination cancellation at 13_000
+    let inside = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let boundary = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let outside = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    // Source cancellation just after the destination one
+    let escrow_address = factory.create_dst_escrow(&dst_immutables(inside), &0, &taker, &13_001);
+    assert_eq!(EscrowClient::new(&e, &escrow_address).get_state(), EscrowState::Active);
+
+    // Source cancellation at the same time
+    let error = factory.try_create_dst_escrow(&dst_immutables(boundary), &0, &taker, &13_000);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidCreationTime.into())));
+
+    // Source cancellation before the destination one
+    let error = factory.try_create_dst_escrow(&dst_immutables(outside), &0, &taker, &12_999);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidCreationTime.into())));
+
+    // Only the valid escrow has been funded
+    assert_eq!(token.balance(&taker), 2500);
+    assert_eq!(safety_token.balance(&taker), 250);
+}
+
+#[test]
+fn test_amend_hashlock_before_withdrawal() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+    let new_secret = generate_secret(&e);
+    let new_hashlock = e.crypto().sha256(&new_secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // An all-zero hashlock is rejected as at creation
+    let error = escrow.try_amend_hashlock(&BytesN::from_array(&e, &[0; 32]), &maker, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::ZeroHashlock.into())));
+
+    // Rotate the hashlock, the signed immutables stay as created
+    escrow.amend_hashlock(&new_hashlock.to_bytes(), &maker, &taker);
+    assert_eq!(escrow.get_resolves().hashlock, new_hashlock.to_bytes());
+    assert_eq!(escrow.get_immutables(), immutables);
+    assert!(escrow.verify_immutables(&immutables));
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    // Old secret no longer unlocks the escrow
+    let error = escrow.try_withdraw(&secret, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidSecret.into())));
+
+    escrow.withdraw(&new_secret, &taker);
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&taker), 500);
+}
+
+#[test]
+fn test_amend_hashlock_after_withdrawal_opens() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+    let new_hashlock = e.crypto().sha256(&generate_secret(&e));
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Withdrawal window opens
+    jump_time(&e, 1000);
+
+    let error = escrow.try_amend_hashlock(&new_hashlock.to_bytes(), &maker, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooLate.into())));
+    assert_eq!(escrow.get_resolves().hashlock, hashlock.to_bytes());
+}
+
+#[test]
+fn test_compute_hashlock_known_vectors() {
+    let e = Env::default();
+
+    let factory = create_escrow_factory_contract(&e);
+
+    // "abc"
+    let secret = bytes!(&e, 0x616263);
+
+    assert_eq!(
+        factory.compute_hashlock(&secret, &HashAlgo::Sha256),
+        bytesn!(&e, 0xba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad)
+    );
+    assert_eq!(
+        factory.compute_hashlock(&secret, &HashAlgo::Keccak256),
+        bytesn!(&e, 0x4e03657aea45a94fc7d47ba826c8d667c0d1e6e33a64a036ec44f58fa12d6c45)
+    );
+}
+
+#[test]
+fn test_safety_deposit_basket_withdraw() {
+    let Setup { e, factory, token_admin, token_sac, token, safety_token_sac, safety_token, maker, taker } = setup();
+
+    let (_extra_token, extra_token) = create_token_contract(&e, &token_admin);
+
+    let public = Address::generate(&e);
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+    _extra_token.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        extra_safety_deposits: vec![&e, (extra_token.address.clone(), 20)],
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Both deposits are locked
+    assert_eq!(safety_token.balance(&escrow_address), 50);
+    assert_eq!(extra_token.balance(&escrow_address), 20);
+
+    // Advance time past public withdrawal timelock
+    jump_time(&e, 2001);
+
+    escrow.withdraw(&secret, &public);
+
+    // Principal goes to taker, the whole basket to the caller
+    assert_eq!(token.balance(&taker), 500);
+    assert_eq!(safety_token.balance(&public), 50);
+    assert_eq!(extra_token.balance(&public), 20);
+    assert_eq!(safety_token.balance(&escrow_address), 0);
+    assert_eq!(extra_token.balance(&escrow_address), 0);
+}
+
+#[test]
+fn test_safety_deposit_basket_cancel() {
+    let Setup { e, factory, token_admin, token_sac, token, safety_token_sac, safety_token, maker, taker } = setup();
+
+    let (_extra_token, extra_token) = create_token_contract(&e, &token_admin);
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+    _extra_token.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        extra_safety_deposits: vec![&e, (extra_token.address.clone(), 20)],
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    assert_eq!(extra_token.balance(&taker), 80);
+
+    // Advance time past cancellation timelock
+    jump_time(&e, 3001);
+
+    escrow.cancel(&taker);
+
+    // Principal back to maker, the whole basket back to the taker
+    assert_eq!(token.balance(&maker), 1000);
+    assert_eq!(safety_token.balance(&taker), 100);
+    assert_eq!(extra_token.balance(&taker), 100);
+    assert_eq!(extra_token.balance(&escrow_address), 0);
+}
+
+#[test]
+fn test_safety_deposit_basket_is_all_or_nothing() {
+    let Setup { e, factory, token_admin, token_sac, token, safety_token_sac, safety_token, maker, taker } = setup();
+
+    let (_extra_token, extra_token) = create_token_contract(&e, &token_admin);
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens, not enough of the extra deposit token
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+    _extra_token.mint(&taker, &10);
+
+    let immutables = EscrowImmutables {
+        extra_safety_deposits: vec![&e, (extra_token.address.clone(), 20)],
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert!(error.is_err());
+
+    // Nothing has been moved
+    assert_eq!(token.balance(&maker), 1000);
+    assert_eq!(safety_token.balance(&taker), 100);
+    assert_eq!(extra_token.balance(&taker), 10);
+}
+
+#[test]
+fn test_implied_rate() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let current_time = e.ledger().timestamp();
+    let dutch_auction = DutchAuction {
+        start_time: current_time,
+        stop_time: current_time + 1000,
+        start_amount: 500,
+        stop_amount: 300,
+    };
+
+    let immutables = EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        amount: AmountCalc::Linear(dutch_auction),
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    assert_eq!(escrow.implied_rate(), (500, 500));
+
+    // Auction midpoint, locked amount is unchanged
+    jump_time(&e, 500);
+    assert_eq!(escrow.implied_rate(), (500, 400));
+
+    // Auction end
+    jump_time(&e, 1000);
+    assert_eq!(escrow.implied_rate(), (500, 300));
+
+    // Principal against safety deposit
+    assert_eq!(escrow.effective_rate(), (500, 50));
+}
+
+#[test]
+fn test_auction_twap() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let current_time = e.ledger().timestamp();
+    let dutch_auction = DutchAuction {
+        start_time: current_time,
+        stop_time: current_time + 1000,
+        start_amount: 500,
+        stop_amount: 300,
+    };
+
+    let mut immutables = EscrowImmutables {
+        amount: AmountCalc::Linear(dutch_auction.clone()),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Midpoint of the auction, whenever the escrow is created
+    jump_time(&e, 250);
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    assert_eq!(escrow.auction_twap(), 400);
+    assert_eq!(escrow.auction_twap(), AmountCalc::Linear(dutch_auction).calc(current_time + 500));
+
+    // Flat amounts are their own average
+    immutables.hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    immutables.amount = AmountCalc::Flat(200);
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    assert_eq!(escrow.auction_twap(), 200);
+
+    // Ends whose sum overflows still average
+    let dutch_auction = DutchAuction {
+        start_time: current_time,
+        stop_time: current_time + 1000,
+        start_amount: i128::MAX,
+        stop_amount: i128::MAX - 2,
+    };
+    assert_eq!(AmountCalc::Linear(dutch_auction).twap(), i128::MAX - 1);
+}
+
+#[test]
+fn test_create_escrows_for_segments() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Order split in two segments, each locked by its own secret
+    let secret_0 = generate_secret(&e);
+    let secret_1 = generate_secret(&e);
+    let hashlock_0 = e.crypto().sha256(&secret_0).to_bytes();
+    let hashlock_1 = e.crypto().sha256(&secret_1).to_bytes();
+    let leaf_0 = merkle_leaf(&e, 0, 300, &hashlock_0);
+    let leaf_1 = merkle_leaf(&e, 1, 200, &hashlock_1);
+    let root = merkle_node(&e, &leaf_0, &leaf_1);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, root, &maker, &token.address, &safety_token.address)
+    };
+
+    let address_0 = factory.create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_1.clone()],
+        &0,
+        &hashlock_0,
+        &300,
+        &taker,
+    );
+    let address_1 = factory.create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_0.clone()],
+        &1,
+        &hashlock_1,
+        &200,
+        &taker,
+    );
+    assert_ne!(address_0, address_1);
+
+    let escrow_0 = EscrowClient::new(&e, &address_0);
+    let escrow_1 = EscrowClient::new(&e, &address_1);
+
+    assert_eq!(escrow_0.get_immutables().hashlock, hashlock_0);
+    assert_eq!(escrow_1.get_immutables().hashlock, hashlock_1);
+    assert_eq!(token.balance(&address_0), 300);
+    assert_eq!(token.balance(&address_1), 200);
+    assert_eq!(token.balance(&maker), 500);
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    // Each segment is unlocked by its own secret
+    let error = escrow_0.try_withdraw(&secret_1, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidSecret.into())));
+
+    escrow_0.withdraw(&secret_0, &taker);
+    escrow_1.withdraw(&secret_1, &taker);
+    assert_eq!(token.balance(&taker), 500);
+}
+
+#[test]
+fn test_segment_address_not_squattable() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret).to_bytes();
+    let leaf_0 = merkle_leaf(&e, 0, 300, &hashlock);
+    let leaf_1 = merkle_leaf(&e, 1, 200, &e.crypto().sha256(&generate_secret(&e)).to_bytes());
+    let root = merkle_node(&e, &leaf_0, &leaf_1);
+
+    // Mint tokens
+    let squatter = Address::generate(&e);
+    token_sac.mint(&maker, &1000);
+    token_sac.mint(&squatter, &1000);
+    safety_token_sac.mint(&taker, &100);
+    safety_token_sac.mint(&squatter, &100);
+
+    // Anyone can fund an escrow of their own under the order hashlock and index 0
+    let immutables = base_immutables(&e, root.clone(), &maker, &token.address, &safety_token.address);
+    let squatting = EscrowImmutables { direction: EscrowDirection::Taker2Maker, ..immutables.clone() };
+    let squatted = factory.create_escrow(&squatting, &0, &squatter);
+    assert_eq!(squatted, factory.predict_address(&root, &0));
+
+    // Which doesn't take the address of segment 0
+    let address = factory.create_escrow_for_segment(&immutables, &vec![&e, leaf_1], &0, &hashlock, &300, &taker);
+    assert_ne!(address, squatted);
+    assert_eq!(address, factory.predict_segment_address(&root, &0));
+    assert_eq!(token.balance(&address), 300);
+}
+
+#[test]
+fn test_create_escrow_for_segment_invalid() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let hashlock_0 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_1 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    // Segments committing to more than the order amount in total
+    let leaf_0 = merkle_leaf(&e, 0, 300, &hashlock_0);
+    let leaf_1 = merkle_leaf(&e, 1, 201, &hashlock_1);
+    let root = merkle_node(&e, &leaf_0, &leaf_1);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, root, &maker, &token.address, &safety_token.address)
+    };
+
+    // Segment hashlock under the wrong index
+    let error = factory.try_create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_0.clone()],
+        &1,
+        &hashlock_0,
+        &300,
+        &taker,
+    );
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidMerkleProof.into())));
+
+    // Segment amount other than the one committed to in its leaf
+    let error = factory.try_create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_1.clone()],
+        &0,
+        &hashlock_0,
+        &500,
+        &taker,
+    );
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidMerkleProof.into())));
+
+    factory.create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_1.clone()],
+        &0,
+        &hashlock_0,
+        &300,
+        &taker,
+    );
+
+    // Fills can't exceed the order amount
+    let error = factory.try_create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_0.clone()],
+        &1,
+        &hashlock_1,
+        &201,
+        &taker,
+    );
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidPartialFill.into())));
+}
+
+#[test]
+fn test_withdraw_with_dispute_window_finalize() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    factory.set_dispute_window(&500);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    escrow.withdraw(&secret, &taker);
+
+    // Principal is held, safety deposit is paid out
+    assert_eq!(escrow.get_state(), EscrowState::Pending);
+    assert_eq!(token.balance(&escrow_address), 500);
+    assert_eq!(safety_token.balance(&taker), 100);
+
+    let error = escrow.try_finalize();
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    // Dispute window is over
+    jump_time(&e, 500);
+
+    escrow.finalize();
+
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&taker), 500);
+    assert_eq!(token.balance(&escrow_address), 0);
+}
+
+#[test]
+fn test_withdraw_with_dispute_window_freeze() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    factory.set_dispute_window(&500);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    escrow.withdraw(&secret, &taker);
+
+    // Admin freezes the funds during the dispute window
+    escrow.freeze();
+    assert_eq!(e.auths()[0].0, factory.admin());
+    assert_eq!(escrow.get_state(), EscrowState::Frozen);
+
+    // Frozen funds are not released after the window
+    jump_time(&e, 500);
+    let error = escrow.try_finalize();
+    assert_eq!(error.err(), Some(Ok(EscrowError::NotPending.into())));
+
+    // Admin settles the dispute in favor of the maker
+    escrow.resolve_dispute(&maker);
+
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&maker), 1000);
+    assert_eq!(token.balance(&taker), 0);
+}
+
+#[test]
+fn test_escrows_of_taker() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, .. } = setup();
+
+    let taker_a = Address::generate(&e);
+    let taker_b = Address::generate(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker_a, &100);
+    safety_token_sac.mint(&taker_b, &100);
+
+    let immutables = |hashlock| EscrowImmutables {
+        amount: AmountCalc::Flat(100),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, hashlock, &maker, &token.address, &safety_token.address)
+    };
+
+    let hashlock_1 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_2 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_3 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    let escrow_1 = factory.create_escrow(&immutables(hashlock_1), &0, &taker_a);
+    let escrow_2 = factory.create_escrow(&immutables(hashlock_2), &0, &taker_b);
+    let escrow_3 = factory.create_escrow(&immutables(hashlock_3), &0, &taker_a);
+
+    assert_eq!(factory.escrows_of_taker(&taker_a), vec![&e, escrow_1, escrow_3]);
+    assert_eq!(factory.escrows_of_taker(&taker_b), vec![&e, escrow_2]);
+    assert_eq!(factory.escrows_of_taker(&maker), vec![&e]);
+}
+
+#[test]
+fn test_is_my_escrow() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let other_factory = create_escrow_factory_contract(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Flat(100),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow = factory.create_escrow(&immutables, &0, &taker);
+    let impostor = other_factory.create_escrow(&immutables, &0, &taker);
+
+    assert!(factory.is_my_escrow(&escrow));
+    assert!(!factory.is_my_escrow(&impostor));
+    assert!(!factory.is_my_escrow(&Address::generate(&e)));
+}
+
+#[test]
+fn test_escrows_of_taker_is_capped() {
+    let Setup { e, factory, token_sac, token, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+
+    let mut first = None;
+    for _ in 0..=MAX_ESCROWS_PER_TAKER {
+        let immutables = EscrowImmutables {
+            amount: AmountCalc::Flat(1),
+            safety_deposit: SafetyDeposit::Flat(0),
+            ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+        };
+        let address = factory.create_escrow(&immutables, &0, &taker);
+        first.get_or_insert(address);
+    }
+
+    // Oldest escrow has been dropped from the index
+    let escrows = factory.escrows_of_taker(&taker);
+    assert_eq!(escrows.len(), MAX_ESCROWS_PER_TAKER);
+    assert!(!escrows.contains(first.unwrap()));
+}
+
+#[test]
+fn test_withdraw_takes_precedence_over_overlapping_cancel() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let public = Address::generate(&e);
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    // Public cancellation misconfigured to open inside the public withdrawal window
+    let immutables = EscrowImmutables {
+        timelocks: TimeLocks {
+            withdrawal: 1000,
+            public_withdrawal: 2000,
+            cancellation: 3000,
+            public_cancellation: 1500,
+        },
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Both public windows are nominally open
+    jump_time(&e, 2500);
+
+    // Cancellation yields while withdrawal is possible
+    let error = escrow.try_cancel(&public);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    escrow.withdraw(&secret, &public);
+
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&taker), 500);
+    assert_eq!(safety_token.balance(&public), 50);
+}
+
+#[test]
+fn test_states_of() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = |hashlock| EscrowImmutables {
+        amount: AmountCalc::Flat(100),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, hashlock, &maker, &token.address, &safety_token.address)
+    };
+
+    let secret_1 = generate_secret(&e);
+    let hashlock_1 = e.crypto().sha256(&secret_1).to_bytes();
+    let hashlock_2 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_3 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    let escrow_1 = factory.create_escrow(&immutables(hashlock_1), &0, &taker);
+    let escrow_2 = factory.create_escrow(&immutables(hashlock_2), &0, &taker);
+    let escrow_3 = factory.create_escrow(&immutables(hashlock_3), &0, &taker);
+
+    // Withdraw the first, cancel the second, leave the third active
+    jump_time(&e, 1500);
+    EscrowClient::new(&e, &escrow_1).withdraw(&secret_1, &taker);
+    jump_time(&e, 2000);
+    EscrowClient::new(&e, &escrow_2).cancel(&taker);
+
+    let states = factory.states_of(&vec![&e, escrow_1, escrow_2, escrow_3.clone()]);
+    assert_eq!(
+        states,
+        vec![&e, EscrowState::Withdrawn, EscrowState::Cancelled, EscrowState::Active]
+    );
+
+    // Batch over the cap is rejected
+    let mut escrows = vec![&e];
+    for _ in 0..=MAX_BATCH_QUERY {
+        escrows.push_back(escrow_3.clone());
+    }
+    let error = factory.try_states_of(&escrows);
+    assert_eq!(error.err(), Some(Ok(EscrowError::BatchTooLarge.into())));
+}
+
+#[test]
+fn test_overview() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = |hashlock, amount| EscrowImmutables {
+        amount: AmountCalc::Flat(amount),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, hashlock, &maker, &token.address, &safety_token.address)
+    };
+
+    // Escrows created at different times, each in its own phase
+    let secret_2 = generate_secret(&e);
+    let escrow_1 = factory.create_escrow(
+        &immutables(e.crypto().sha256(&generate_secret(&e)).to_bytes(), 100),
+        &0,
+        &taker,
+    );
+    jump_time(&e, 1500);
+    let escrow_2 = factory.create_escrow(&immutables(e.crypto().sha256(&secret_2).to_bytes(), 200), &0, &taker);
+    jump_time(&e, 1000);
+    let escrow_3 = factory.create_escrow(
+        &immutables(e.crypto().sha256(&generate_secret(&e)).to_bytes(), 300),
+        &0,
+        &taker,
+    );
+    EscrowClient::new(&e, &escrow_2).withdraw(&secret_2, &taker);
+
+    let escrows = vec![&e, escrow_1, escrow_2, escrow_3];
+    let overview = factory.overview(&escrows);
+    assert_eq!(
+        overview,
+        vec![
+            &e,
+            EscrowOverview {
+                state: EscrowState::Active,
+                phase: EscrowPhase::PublicWithdrawal,
+                amount: 100,
+            },
+            EscrowOverview {
+                state: EscrowState::Withdrawn,
+                phase: EscrowPhase::Withdrawal,
+                amount: 200,
+            },
+            EscrowOverview {
+                state: EscrowState::Active,
+                phase: EscrowPhase::Locked,
+                amount: 300,
+            },
+        ]
+    );
+
+    // Same as the individual getters
+    for (escrow, entry) in escrows.iter().zip(overview.iter()) {
+        let escrow = EscrowClient::new(&e, &escrow);
+        assert_eq!(entry.state, escrow.get_state());
+        assert_eq!(entry.phase, escrow.phase());
+        assert_eq!(entry.amount, escrow.get_resolves().amount);
+    }
+}
+
+#[test]
+fn test_create_escrow_for_segment_max_parts() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Order split in four segments
+    let hashlock_0 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_1 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_2 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_3 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let leaf_0 = merkle_leaf(&e, 0, 100, &hashlock_0);
+    let leaf_1 = merkle_leaf(&e, 1, 100, &hashlock_1);
+    let leaf_2 = merkle_leaf(&e, 2, 100, &hashlock_2);
+    let leaf_3 = merkle_leaf(&e, 3, 100, &hashlock_3);
+    let node_01 = merkle_node(&e, &leaf_0, &leaf_1);
+    let node_23 = merkle_node(&e, &leaf_2, &leaf_3);
+    let root = merkle_node(&e, &node_01, &node_23);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Flat(400),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, root, &maker, &token.address, &safety_token.address)
+    };
+
+    assert_eq!(factory.max_parts(), DEFAULT_MAX_PARTS);
+    factory.set_max_parts(&2);
+    assert_eq!(factory.max_parts(), 2);
+
+    // Parts within the cap are accepted
+    factory.create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_0.clone(), node_23.clone()],
+        &1,
+        &hashlock_1,
+        &100,
+        &taker,
+    );
+
+    // Parts over the cap are rejected
+    let error = factory.try_create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_3.clone(), node_01.clone()],
+        &2,
+        &hashlock_2,
+        &100,
+        &taker,
+    );
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooManyParts.into())));
+}
+
+#[test]
+fn test_cancel_segments() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Order split in four segments
+    let hashlock_0 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_1 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_2 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_3 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let leaf_0 = merkle_leaf(&e, 0, 100, &hashlock_0);
+    let leaf_1 = merkle_leaf(&e, 1, 100, &hashlock_1);
+    let leaf_2 = merkle_leaf(&e, 2, 100, &hashlock_2);
+    let leaf_3 = merkle_leaf(&e, 3, 100, &hashlock_3);
+    let node_01 = merkle_node(&e, &leaf_0, &leaf_1);
+    let node_23 = merkle_node(&e, &leaf_2, &leaf_3);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Flat(400),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, merkle_node(&e, &node_01, &node_23), &maker, &token.address, &safety_token.address)
+    };
+
+    factory.create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_1.clone(), node_23.clone()],
+        &0,
+        &hashlock_0,
+        &100,
+        &taker,
+    );
+    assert_eq!(token.balance(&maker), 900);
+
+    // Filled segments can't be cancelled, nor can segments past the order
+    let error = factory.try_cancel_segments(&immutables, &0, &2);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidPartialFill.into())));
+    let error = factory.try_cancel_segments(&immutables, &1, &(DEFAULT_MAX_PARTS + 1));
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidPartialFill.into())));
+    let error = factory.try_cancel_segments(&immutables, &2, &2);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidPartialFill.into())));
+
+    // Cancel segments 1 and 2, the maker keeping their tokens
+    factory.cancel_segments(&immutables, &1, &3);
+    assert!(!factory.is_segment_cancelled(&immutables, &0));
+    assert!(factory.is_segment_cancelled(&immutables, &1));
+    assert!(factory.is_segment_cancelled(&immutables, &2));
+    assert!(!factory.is_segment_cancelled(&immutables, &3));
+    assert_eq!(token.balance(&maker), 900);
+
+    let error = factory.try_create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_0.clone(), node_23.clone()],
+        &1,
+        &hashlock_1,
+        &100,
+        &taker,
+    );
+    assert_eq!(error.err(), Some(Ok(EscrowError::SegmentCancelled.into())));
+    let error = factory.try_create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_3.clone(), node_01.clone()],
+        &2,
+        &hashlock_2,
+        &100,
+        &taker,
+    );
+    assert_eq!(error.err(), Some(Ok(EscrowError::SegmentCancelled.into())));
+
+    // Segments outside the range stay fillable
+    factory.create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_2.clone(), node_01.clone()],
+        &3,
+        &hashlock_3,
+        &100,
+        &taker,
+    );
+    assert_eq!(token.balance(&maker), 800);
+}
+
+#[test]
+fn test_required_funding() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let hashlock = e.crypto().sha256(&generate_secret(&e));
+
+    // Mint tokens
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let current_time = e.ledger().timestamp();
+    let dutch_auction = DutchAuction {
+        start_time: current_time,
+        stop_time: current_time + 1000,
+        start_amount: 500,
+        stop_amount: 300,
+    };
+
+    let immutables = EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        amount: AmountCalc::Linear(dutch_auction),
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Midway through the auction
+    jump_time(&e, 250);
+
+    let (principal, safety_deposit) = factory.required_funding(&immutables);
+    assert_eq!((principal, safety_deposit), (450, 50));
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+
+    // Matches what create_escrow pulled
+    assert_eq!(token.balance(&escrow_address), principal);
+    assert_eq!(safety_token.balance(&escrow_address), safety_deposit);
+    assert_eq!(token.balance(&taker), 1000 - principal);
+    assert_eq!(safety_token.balance(&taker), 100 - safety_deposit);
+}
+
+#[test]
+fn test_safety_deposit_percent() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, .. } = setup();
+
+    let taker = Address::generate(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    // 5% of the principal
+    assert_eq!(SafetyDeposit::Percent(500).amount(&e, 1000), 50);
+    assert_eq!(SafetyDeposit::Flat(50).amount(&e, 1000), 50);
+
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Flat(1000),
+        safety_deposit: SafetyDeposit::Percent(500),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+    assert_eq!(factory.required_funding(&immutables), (1000, 50));
+    assert_eq!(EscrowImmutables::decode(&e, &immutables.encode(&e)), immutables);
+
+    // At most the whole principal
+    let error = factory.try_create_escrow(&EscrowImmutables { safety_deposit: SafetyDeposit::Percent(10_001), ..immutables.clone() }, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+
+    // A share of a principal too large to scale fails rather than wrapping
+    let error = factory.try_required_funding(&EscrowImmutables { amount: AmountCalc::Flat(i128::MAX), ..immutables.clone() });
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+
+    // The escrow holds the deposit as the amount it came to at creation, the
+    // signed immutables stay as created
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    assert_eq!(safety_token.balance(&escrow_address), 50);
+    assert_eq!(escrow.get_resolves().safety_deposit, 50);
+    assert_eq!(escrow.get_immutables(), immutables);
+    assert!(escrow.verify_immutables(&immutables));
+
+    // The deposit doesn't follow the principal once part of it is split off
+    let secret = generate_secret(&e);
+    let immutables = EscrowImmutables {
+        hashlock: e.crypto().sha256(&secret).to_bytes(),
+        ..immutables
+    };
+    let taker = Address::generate(&e);
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &50);
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    factory.bind(&immutables.hashlock, 1);
+    escrow.split_escrow(&400, &Address::generate(&e), &1);
+
+    jump_time(&e, 1001);
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(token.balance(&taker), 600);
+    assert_eq!(safety_token.balance(&taker), 50);
+}
+
+#[test]
+fn test_quote() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    jump_time(&e, 100);
+    let current_time = e.ledger().timestamp();
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Linear(DutchAuction {
+            start_time: current_time - 100,
+            stop_time: current_time + 300,
+            start_amount: 800,
+            stop_amount: 400,
+        }),
+        safety_deposit: SafetyDeposit::Percent(1000),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let quote = factory.quote(&immutables, &0);
+    assert_eq!(quote.amount, 700);
+    assert_eq!(quote.safety_deposit, 70);
+
+    // Creating the escrow in the same ledger matches the quote
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    let resolves = escrow.get_resolves();
+    let timelocks = escrow.get_timelocks();
+    assert_eq!(quote.address, escrow_address);
+    assert_eq!(token.balance(&escrow_address), quote.amount);
+    assert_eq!(safety_token.balance(&escrow_address), quote.safety_deposit);
+    assert_eq!(
+        quote.timelocks,
+        TimeLocks {
+            withdrawal: resolves.timestamp + timelocks.withdrawal,
+            public_withdrawal: resolves.timestamp + timelocks.public_withdrawal,
+            cancellation: resolves.timestamp + timelocks.cancellation,
+            public_cancellation: resolves.timestamp + timelocks.public_cancellation,
+        }
+    );
+}
+
+#[test]
+fn test_quote_at() {
+    let e = Env::default();
+    let factory = create_escrow_factory_contract(&e);
+    let hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    let mut immutables = EscrowImmutablesBuilder::new(&e, hashlock, Address::generate(&e), Address::generate(&e), 0)
+        .amount(AmountCalc::Linear(DutchAuction { start_time: 1000, stop_time: 2000, start_amount: 1000, stop_amount: 500 }))
+        .build();
+
+    // Start, mid and end of the curve, regardless of the ledger time
+    assert_eq!(factory.quote_at(&immutables, &1000), 1000);
+    assert_eq!(factory.quote_at(&immutables, &1500), 750);
+    assert_eq!(factory.quote_at(&immutables, &2000), 500);
+
+    // Clamped outside the auction
+    assert_eq!(factory.quote_at(&immutables, &0), 1000);
+    assert_eq!(factory.quote_at(&immutables, &u64::MAX), 500);
+
+    // Auctions running backwards have no curve
+    immutables.amount = AmountCalc::Linear(DutchAuction { start_time: 2000, stop_time: 1000, start_amount: 1000, stop_amount: 500 });
+    let error = factory.try_quote_at(&immutables, &1500);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+}
+
+#[test]
+fn test_expire_by_anyone() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let samaritan = Address::generate(&e);
+    let hashlock = e.crypto().sha256(&generate_secret(&e));
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Taker's cancellation window alone isn't enough
+    jump_time(&e, 3001);
+    let error = escrow.try_expire(&samaritan);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    // Advance time past public cancellation timelock
+    jump_time(&e, 1000);
+    escrow.expire(&samaritan);
+
+    assert_eq!(escrow.get_state(), EscrowState::Cancelled);
+    assert_eq!(token.balance(&maker), 1000);
+    assert_eq!(safety_token.balance(&samaritan), 50);
+
+    // Expiring a settled escrow does nothing
+    escrow.expire(&taker);
+    assert_eq!(escrow.get_state(), EscrowState::Cancelled);
+    assert_eq!(safety_token.balance(&taker), 50);
+}
+
+#[test]
+fn test_create_escrow_dust_safety_deposit() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = |safety_deposit_amount| EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(safety_deposit_amount),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    assert_eq!(factory.min_transfer(&safety_token.address), 0);
+    factory.set_min_transfer(&safety_token.address, &10);
+    assert_eq!(factory.min_transfer(&safety_token.address), 10);
+
+    // Dust safety deposit is rejected before any transfer
+    let error = factory.try_create_escrow(&immutables(1), &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::BelowMinTransfer.into())));
+    assert_eq!(token.balance(&maker), 1000);
+    assert_eq!(safety_token.balance(&taker), 100);
+
+    // Deposit at the minimum is accepted
+    let escrow_address = factory.create_escrow(&immutables(10), &0, &taker);
+    assert_eq!(safety_token.balance(&escrow_address), 10);
+
+    // No deposit at all isn't dust
+    let escrow_address = factory.create_escrow(&immutables(0), &0, &taker);
+    assert_eq!(safety_token.balance(&escrow_address), 0);
+    assert_eq!(safety_token.balance(&taker), 90);
+
+    // Negative minimum is rejected
+    let error = factory.try_set_min_transfer(&safety_token.address, &-1);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+}
+
+#[test]
+fn test_min_transfer_at_settlement() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let keeper = Address::generate(&e);
+    let secret = generate_secret(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &2000);
+    safety_token_sac.mint(&taker, &200);
+
+    factory.set_min_transfer(&token.address, &10);
+    factory.set_min_transfer(&safety_token.address, &10);
+    factory.set_keeper_reward(&5);
+    factory.set_protocol_fee(&ProtocolFee { bps: 100, rounding: FeeRounding::RoundDown });
+
+    let immutables = EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(100),
+        ..base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+
+    // Neither part of a split may be dust
+    let error = escrow.try_split_escrow(&9, &Address::generate(&e), &1);
+    assert_eq!(error.err(), Some(Ok(EscrowError::BelowMinTransfer.into())));
+    let error = escrow.try_split_escrow(&491, &Address::generate(&e), &1);
+    assert_eq!(error.err(), Some(Ok(EscrowError::BelowMinTransfer.into())));
+
+    // A dust fee is waived
+    jump_time(&e, 1000);
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(token.balance(&taker), 500);
+    assert_eq!(factory.collected_fees(&token.address), 0);
+
+    // A dust keeper reward is folded into the taker's share
+    let immutables = EscrowImmutables {
+        hashlock: e.crypto().sha256(&generate_secret(&e)).to_bytes(),
+        ..immutables
+    };
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    jump_time(&e, 4000);
+    escrow.keeper_cancel(&keeper);
+    assert_eq!(safety_token.balance(&keeper), 0);
+    assert_eq!(safety_token.balance(&taker), 200);
+    assert_eq!(token.balance(&maker), 1500);
+}
+
+#[test]
+fn test_restore_then_withdraw() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    let ttl = || e.as_contract(&escrow_address, || e.storage().instance().get_ttl());
+    assert_eq!(ttl(), ESCROW_TTL_EXTEND_TO);
+
+    // The factory must be live too, the withdrawal reveals the secret to it
+    e.as_contract(&factory.address, || {
+        e.storage().instance().extend_ttl(ESCROW_TTL_THRESHOLD, ESCROW_TTL_EXTEND_TO)
+    });
+
+    // Let the storage lifetime run low
+    let elapsed = ESCROW_TTL_EXTEND_TO - ESCROW_TTL_THRESHOLD + 1;
+    e.ledger().set_sequence_number(e.ledger().sequence() + elapsed);
+    assert_eq!(ttl(), ESCROW_TTL_EXTEND_TO - elapsed);
+
+    escrow.restore();
+    assert_eq!(ttl(), ESCROW_TTL_EXTEND_TO);
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&taker), 500);
+}
+
+#[test]
+fn test_immutables_builder() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let backup = Address::generate(&e);
+    let hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    // Defaults fill everything but the order's core
+    let built = EscrowImmutablesBuilder::new(&e, hashlock.clone(), maker.clone(), token.address.clone(), 500).build();
+    assert_eq!(
+        built,
+        EscrowImmutables {
+            safety_deposit: SafetyDeposit::Flat(0),
+            timelocks: DEFAULT_TIMELOCKS,
+            ..base_immutables(&e, hashlock.clone(), &maker, &token.address, &token.address)
+        }
+    );
+
+    let timelocks = TimeLocks {
+        withdrawal: 1000,
+        public_withdrawal: 2000,
+        cancellation: 3000,
+        public_cancellation: 4000,
+    };
+    let built = EscrowImmutablesBuilder::new(&e, hashlock.clone(), maker.clone(), token.address.clone(), 500)
+        .direction(EscrowDirection::Taker2Maker)
+        .backup_taker(backup.clone(), 1500)
+        .safety_deposit(safety_token.address.clone(), 50)
+        .extra_safety_deposit(token.address.clone(), 5)
+        .timelocks(timelocks.clone())
+        .build();
+    assert_eq!(
+        built,
+        EscrowImmutables {
+            direction: EscrowDirection::Taker2Maker,
+            backup_taker: Some(backup),
+            backup_withdrawal: 1500,
+            extra_safety_deposits: vec![&e, (token.address.clone(), 5)],
+            timelocks,
+            ..base_immutables(&e, hashlock, &maker, &token.address, &safety_token.address)
+        }
+    );
+
+    // Built immutables are accepted at creation
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&built, &0, &taker));
+    assert_eq!(escrow.get_immutables(), built);
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #25)")]
+fn test_immutables_builder_invalid() {
+    let e = Env::default();
+
+    EscrowImmutablesBuilder::new(
+        &e,
+        BytesN::from_array(&e, &[0; 32]),
+        Address::generate(&e),
+        Address::generate(&e),
+        500,
+    )
+    .build();
+}
+
+#[test]
+fn test_immutables_encoding_round_trip() {
+    let e = Env::default();
+
+    let token_admin = Address::generate(&e);
+    let (_token, token) = create_token_contract(&e, &token_admin);
+    let (_safety_token, safety_token) = create_token_contract(&e, &token_admin);
+    let (_extra_token, extra_token) = create_token_contract(&e, &token_admin);
+    let maker = Address::generate(&e);
+
+    let flat = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address);
+    let linear = EscrowImmutables {
+        second_hashlock: Some(e.crypto().sha256(&generate_secret(&e)).to_bytes()),
+        hash_algo: HashAlgo::Keccak256,
+        direction: EscrowDirection::Taker2Maker,
+        refund_address_hint: bytes!(&e, 0x742d35cc6634c0532925a3b844bc454e4438f44e),
+        backup_taker: Some(Address::generate(&e)),
+        backup_withdrawal: 500,
+        amount: AmountCalc::Linear(DutchAuction {
+            start_time: 100,
+            stop_time: 1100,
+            start_amount: 500,
+            stop_amount: -300,
+        }),
+        safety_deposit: SafetyDeposit::Flat(0),
+        extra_safety_deposits: vec![&e, (extra_token.address.clone(), 20), (token.address.clone(), 5)],
+        timelocks: TimeLocks {
+            withdrawal: 0,
+            public_withdrawal: u64::MAX,
+            cancellation: 1,
+            public_cancellation: u64::MAX - 1,
+        },
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    for immutables in [flat, linear] {
+        let encoded = immutables.encode(&e);
+
+        // Same immutables always encode to the same bytes
+        assert_eq!(encoded, immutables.clone().encode(&e));
+        assert_eq!(EscrowImmutables::decode(&e, &encoded), immutables);
+    }
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #16)")]
+fn test_decode_malformed_address() {
+    let e = Env::default();
+
+    let immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &Address::generate(&e), &Address::generate(&e), &Address::generate(&e));
+    let mut encoded = immutables.encode(&e);
+
+    // Maker strkey follows the hashlock, hash flags and its length byte, one
+    // character off fails its checksum instead of trapping in the host
+    assert_eq!(encoded.get(35), Some(56));
+    let byte = encoded.get(46).unwrap();
+    encoded.set(46, if byte == b'A' { b'B' } else { b'A' });
+    EscrowImmutables::decode(&e, &encoded);
+}
+
+#[test]
+fn test_order_hash() {
+    let e = Env::default();
+
+    let factory = create_escrow_factory_contract(&e);
+    let token_admin = Address::generate(&e);
+    let (_token, token) = create_token_contract(&e, &token_admin);
+    let (_safety_token, safety_token) = create_token_contract(&e, &token_admin);
+
+    let mut immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &Address::generate(&e), &token.address, &safety_token.address);
+
+    let order_hash = factory.order_hash(&immutables);
+    assert_eq!(order_hash, e.crypto().sha256(&immutables.encode(&e)).to_bytes());
+
+    // Any field change changes the hash
+    immutables.safety_deposit = SafetyDeposit::Flat(51);
+    assert_ne!(factory.order_hash(&immutables), order_hash);
+}
+
+#[test]
+fn test_create_escrow_min_amount() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = |amount| EscrowImmutables {
+        amount: AmountCalc::Flat(amount),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    assert_eq!(factory.min_amount(), 0);
+    factory.set_min_amount(&100);
+    assert_eq!(factory.min_amount(), 100);
+
+    // Below the floor
+    let error = factory.try_create_escrow(&immutables(99), &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::AmountTooSmall.into())));
+
+    // At and above the floor
+    let at = factory.create_escrow(&immutables(100), &0, &taker);
+    let above = factory.create_escrow(&immutables(101), &0, &taker);
+    assert_eq!(token.balance(&at), 100);
+    assert_eq!(token.balance(&above), 101);
+
+    // Negative floors are rejected
+    let error = factory.try_set_min_amount(&-1);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+    assert_eq!(factory.min_amount(), 100);
+}
+
+#[test]
+fn test_caller_economics() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let public = Address::generate(&e);
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    let economics = |can_withdraw| CallerEconomics {
+        payee: maker.clone(),
+        safety_deposits: vec![&e, (safety_token.address.clone(), 50)],
+        can_withdraw,
+    };
+
+    // Nobody before the withdrawal window
+    assert_eq!(escrow.caller_economics(&taker), economics(false));
+    assert_eq!(escrow.caller_economics(&public), economics(false));
+
+    // Only the taker in the private window
+    jump_time(&e, 1000);
+    assert_eq!(escrow.caller_economics(&taker), economics(true));
+    assert_eq!(escrow.caller_economics(&public), economics(false));
+
+    // Anyone in the public window
+    jump_time(&e, 1000);
+    assert_eq!(escrow.caller_economics(&taker), economics(true));
+    assert_eq!(escrow.caller_economics(&public), economics(true));
+
+    // Nobody once cancellation opens
+    jump_time(&e, 1000);
+    assert_eq!(escrow.caller_economics(&taker), economics(false));
+    assert_eq!(escrow.caller_economics(&public), economics(false));
+}
+
+#[test]
+fn test_caller_economics_after_withdraw() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let public = Address::generate(&e);
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    // Public callers' reward grows over the first 1000 seconds
+    factory.set_reward_ramp(&1000);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Advance time halfway through the reward ramp of the public withdrawal
+    jump_time(&e, 2500);
+
+    // The public caller would get the reward reached so far, the taker the whole deposit
+    let economics = escrow.caller_economics(&public);
+    assert_eq!(economics.payee, taker);
+    assert_eq!(economics.safety_deposits, vec![&e, (safety_token.address.clone(), 25)]);
+    assert!(economics.can_withdraw);
+    assert_eq!(
+        escrow.caller_economics(&taker).safety_deposits,
+        vec![&e, (safety_token.address.clone(), 50)]
+    );
+
+    escrow.withdraw(&secret, &public);
+    assert_eq!(safety_token.balance(&public), 25);
+
+    // Nothing left to withdraw
+    assert!(!escrow.caller_economics(&public).can_withdraw);
+    assert!(!escrow.caller_economics(&taker).can_withdraw);
+}
+
+#[test]
+fn test_caller_role() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let hashlock = e.crypto().sha256(&generate_secret(&e));
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    assert_eq!(escrow.caller_role(&taker), CallerRole::Taker);
+    assert_eq!(escrow.caller_role(&maker), CallerRole::Maker);
+    assert_eq!(escrow.caller_role(&Address::generate(&e)), CallerRole::Public);
+}
+
+#[test]
+fn test_create_escrow_invalid_dutch_auction() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let current_time = e.ledger().timestamp();
+    let immutables = |start_amount, stop_amount| EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        amount: AmountCalc::Linear(DutchAuction {
+            start_time: current_time,
+            stop_time: current_time + 1000,
+            start_amount,
+            stop_amount,
+        }),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Negative endpoint
+    let error = factory.try_create_escrow(&immutables(500, -100), &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+
+    // Increasing amount
+    let error = factory.try_create_escrow(&immutables(300, 500), &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+
+    factory.create_escrow(&immutables(500, 0), &0, &taker);
+}
+
+#[test]
+fn test_dutch_auction_clock_sources() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    e.ledger().set_timestamp(10_000);
+    e.ledger().set_sequence_number(100);
+
+    // Same auction window on either clock
+    let immutables = |start| EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        amount: AmountCalc::Linear(DutchAuction {
+            start_time: start,
+            stop_time: start + 1000,
+            start_amount: 500,
+            stop_amount: 300,
+        }),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Timestamp moves, sequence doesn't
+    jump_time(&e, 250);
+    assert_eq!(factory.clock_source(), ClockSource::Timestamp);
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables(10_000), &0, &taker));
+    assert_eq!(escrow.get_resolves().amount, 450);
+
+    // Sequence moves, timestamp doesn't
+    factory.set_clock_source(&ClockSource::Sequence);
+    assert_eq!(factory.clock_source(), ClockSource::Sequence);
+    e.ledger().set_sequence_number(600);
+    let escrow_seq = EscrowClient::new(&e, &factory.create_escrow(&immutables(100), &0, &taker));
+    assert_eq!(escrow_seq.get_resolves().amount, 400);
+    assert_eq!(escrow_seq.implied_rate(), (400, 400));
+
+    // Escrows keep the clock they were created with
+    assert_eq!(escrow.get_resolves().clock_source, ClockSource::Timestamp);
+    assert_eq!(escrow_seq.get_resolves().clock_source, ClockSource::Sequence);
+    assert_eq!(escrow.implied_rate(), (450, 450));
+}
+
+#[test]
+fn test_withdraw_multi() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret_1 = generate_secret(&e);
+    let secret_2 = generate_secret(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        second_hashlock: Some(e.crypto().sha256(&secret_2).to_bytes()),
+        ..base_immutables(&e, e.crypto().sha256(&secret_1).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    // One secret alone isn't enough
+    let error = escrow.try_withdraw(&secret_1, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidSecret.into())));
+
+    // One of the two secrets is wrong
+    let error = escrow.try_withdraw_multi(&vec![&e, secret_1.clone(), secret_1.clone()], &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidSecret.into())));
+
+    escrow.withdraw_multi(&vec![&e, secret_1.clone(), secret_2.clone()], &taker);
+
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&taker), 500);
+    assert!(factory.is_revealed(&e.crypto().sha256(&secret_2).to_bytes()));
+}
+
+#[test]
+fn test_withdraw_multi_one_secret_revealed() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret_1 = generate_secret(&e);
+    let secret_2 = generate_secret(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let sibling_immutables = base_immutables(&e, e.crypto().sha256(&secret_1).to_bytes(), &maker, &token.address, &safety_token.address);
+    let immutables = EscrowImmutables {
+        second_hashlock: Some(e.crypto().sha256(&secret_2).to_bytes()),
+        ..sibling_immutables.clone()
+    };
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    let sibling = EscrowClient::new(&e, &factory.create_escrow(&sibling_immutables, &1, &taker));
+
+    // A sibling sharing only the first hashlock reveals the first secret
+    jump_time(&e, 1001);
+    sibling.withdraw(&secret_1, &taker);
+    assert!(factory.is_revealed(&immutables.hashlock));
+
+    // Which leaves the escrow cancellable, withdrawal still needing the second secret
+    jump_time(&e, 2000);
+    let error = escrow.try_withdraw_multi(&vec![&e, secret_1.clone(), generate_secret(&e)], &taker);
+    assert!(error.is_err());
+    escrow.cancel(&taker);
+    assert_eq!(escrow.get_state(), EscrowState::Cancelled);
+    assert_eq!(token.balance(&maker), 500);
+}
+
+#[test]
+fn test_create_escrow_event_carries_auction_amount() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    // Mint tokens
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let current_time = e.ledger().timestamp();
+    let immutables = EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        amount: AmountCalc::Linear(DutchAuction {
+            start_time: current_time,
+            stop_time: current_time + 1000,
+            start_amount: 500,
+            stop_amount: 300,
+        }),
+        ..base_immutables(&e, hashlock.clone(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Midway through the auction
+    jump_time(&e, 500);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+
+    let (contract, topics, data) = e.events().all().last().unwrap();
+    assert_eq!(contract, factory.address);
+    assert_eq!(
+        topics,
+        (Symbol::new(&e, "htlc"), Symbol::new(&e, "escrow_created"), escrow_address).into_val(&e)
+    );
+    let data = <(BytesN<32>, i128, u64, Option<Address>)>::try_from_val(&e, &data).unwrap();
+    assert_eq!(data, (hashlock, 400, current_time + 500, None));
+}
+
+#[test]
+fn test_create_escrow_negative_safety_deposit() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let hashlock = e.crypto().sha256(&generate_secret(&e));
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(-50),
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidImmutables.into())));
+    assert_eq!(safety_token.balance(&taker), 100);
+}
+
+#[test]
+fn test_timelocks_past_u32_range() {
+    let e = Env::default();
+
+    let deployed_at = u32::MAX - 100;
+    let mut timelocks = Timelocks::new(&e, deployed_at);
+
+    timelocks.set_stage(Stage::SrcWithdrawal, 300);
+
+    let start = u32::MAX as u64 + 200;
+    assert_eq!(timelocks.get_timestamp(Stage::SrcWithdrawal), start);
+    assert!(!timelocks.has_started(Stage::SrcWithdrawal, start - 1));
+    assert!(timelocks.has_started(Stage::SrcWithdrawal, start));
+    assert_eq!(timelocks.rescue_start_timestamp(u32::MAX), deployed_at as u64 + u32::MAX as u64);
+}
+
+#[test]
+fn test_interface_immutables_periods_past_u32_range() {
+    let e = Env::default();
+
+    let deployed_at = u32::MAX - 100;
+    let mut timelocks = Timelocks::new(&e, deployed_at);
+    timelocks.set_stage(Stage::SrcWithdrawal, 300);
+    timelocks.set_stage(Stage::SrcPublicWithdrawal, 400);
+    timelocks.set_stage(Stage::SrcCancellation, 500);
+    timelocks.set_stage(Stage::SrcPublicCancellation, 600);
+
+    let secret = bytesn!(&e, 0x0101010101010101010101010101010101010101010101010101010101010101);
+    let immutables = Immutables {
+        order_hash: bytesn!(&e, 0x0000000000000000000000000000000000000000000000000000000000000000),
+        hashlock: e.crypto().sha256(&secret.clone().into()).into(),
+        maker: Address::generate(&e),
+        taker: Address::generate(&e),
+        token: Address::generate(&e),
+        amount: 500,
+        safety_deposit: 50,
+        timelocks,
+    };
+
+    // Stage starts lie past u32::MAX
+    e.ledger().set_timestamp(u32::MAX as u64 + 199);
+    assert!(!immutables.is_withdrawal_period(&e, true));
+
+    e.ledger().set_timestamp(u32::MAX as u64 + 200);
+    assert!(immutables.is_withdrawal_period(&e, true));
+    assert!(!immutables.is_public_withdrawal_period(&e, true));
+
+    e.ledger().set_timestamp(u32::MAX as u64 + 500);
+    assert!(immutables.is_public_withdrawal_period(&e, true));
+    assert!(immutables.is_cancellation_period(&e, true));
+    assert!(immutables.is_public_cancellation_period(&e));
+    assert!(immutables.is_rescue_available(&e, 600));
+    assert!(!immutables.is_rescue_available(&e, 601));
+
+    assert!(immutables.verify_secret(&e, &secret));
+}
+
+#[test]
+fn test_get_hash_algo_keccak_withdraw() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().keccak256(&secret).to_bytes();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        hash_algo: HashAlgo::Keccak256,
+        ..base_immutables(&e, hashlock.clone(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    assert_eq!(escrow.get_hash_algo(), HashAlgo::Keccak256);
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(token.balance(&taker), 500);
+    assert!(factory.is_revealed(&hashlock));
+}
+
+#[test]
+fn test_cancel_emits_refund_address_hint() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let hashlock = e.crypto().sha256(&generate_secret(&e));
+    let refund_address_hint = bytes!(&e, 0x742d35cc6634c0532925a3b844bc454e4438f44e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        refund_address_hint: refund_address_hint.clone(),
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Advance time past cancellation timelock
+    jump_time(&e, 3001);
+
+    escrow.cancel(&taker);
+
+    let (contract, topics, data) = e.events().all().last().unwrap();
+    assert_eq!(contract, escrow_address);
+    assert_eq!(topics, (Symbol::new(&e, "htlc"), Symbol::new(&e, "cancel")).into_val(&e));
+    let (hint,) = <(Bytes,)>::try_from_val(&e, &data).unwrap();
+    assert_eq!(hint, refund_address_hint);
+
+    // Refund still goes to the maker on this chain
+    assert_eq!(token.balance(&maker), 1000);
+}
+
+#[test]
+fn test_transfer_admin_two_step() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let new_admin = Address::generate(&e);
+    let stranger = Address::generate(&e);
+    let factory = TestFactory::new(
+        &e,
+        &e.register(EscrowFactory, (admin.clone(), Symbol::new(&e, "htlc"), upload_escrow_wasm(&e))),
+    );
+
+    // Only the current admin can propose a transfer
+    let error = factory.try_transfer_admin(&stranger, &new_admin);
+    assert_eq!(error.err(), Some(Ok(EscrowError::Unauthorized.into())));
+
+    // Proposing alone doesn't hand over the factory
+    factory.transfer_admin(&admin, &new_admin);
+    assert_eq!(e.auths()[0].0, admin);
+    assert_eq!(factory.admin(), admin);
+    assert_eq!(factory.pending_admin(), Some(new_admin.clone()));
+    factory.set_dispute_window(&600);
+    assert_eq!(e.auths()[0].0, admin);
+
+    // Nobody but the proposed admin can accept
+    let error = factory.try_accept_admin(&stranger);
+    assert_eq!(error.err(), Some(Ok(EscrowError::Unauthorized.into())));
+
+    // Accepting completes the rotation
+    factory.accept_admin(&new_admin);
+    assert_eq!(e.auths()[0].0, new_admin);
+    assert_eq!(factory.admin(), new_admin);
+    assert_eq!(factory.pending_admin(), None);
+    factory.set_dispute_window(&900);
+    assert_eq!(e.auths()[0].0, new_admin);
+
+    // The former admin lost its rights
+    let error = factory.try_transfer_admin(&admin, &stranger);
+    assert_eq!(error.err(), Some(Ok(EscrowError::Unauthorized.into())));
+}
+
+#[test]
+fn test_config() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let factory = TestFactory::new(
+        &e,
+        &e.register(EscrowFactory, (admin.clone(), Symbol::new(&e, "htlc_a"), upload_escrow_wasm(&e))),
+    );
+
+    // Defaults next to the admin set at initialize
+    assert_eq!(
+        factory.config(),
+        FactoryConfig {
+            admin: admin.clone(),
+            dispute_window: 0,
+            max_parts: DEFAULT_MAX_PARTS,
+            min_amount: 0,
+            clock_source: ClockSource::Timestamp,
+            public_cooldown: 0,
+            ttl_bump: ESCROW_TTL_EXTEND_TO,
+            topic_prefix: Symbol::new(&e, "htlc_a"),
+            escrow_wasm_hash: upload_escrow_wasm(&e),
+            amount_validity: 0,
+            reward_ramp: 0,
+            maker_auth_cancel: false,
+            max_future: 0,
+            cancel_oracle: None,
+            no_safety_deposit: false,
+            protocol_fee: ProtocolFee { bps: 0, rounding: FeeRounding::RoundDown },
+            guardian: None,
+            require_eoa: false,
+            secret_registry: None,
+            keeper_reward: 0,
+            deposit_tokens: vec![&e],
+            min_transfers: Map::new(&e),
+            pending_admin: None,
+            rebate_token: None,
+            rebate_amount: 0,
+            rebate_prompt_window: 0,
+            rebate_pool: 0,
+        }
+    );
+
+    factory.set_dispute_window(&600);
+    factory.set_max_parts(&8);
+    factory.set_min_amount(&100);
+    factory.set_clock_source(&ClockSource::Sequence);
+    factory.set_public_cooldown(&60);
+    factory.set_ttl_bump(&100_000);
+    factory.set_amount_validity(&3600);
+    factory.set_reward_ramp(&1200);
+    factory.set_maker_auth_cancel(&true);
+    factory.set_max_future(&86_400);
+    factory.set_cancel_oracle(&BytesN::from_array(&e, &[3; 32]));
+    factory.set_no_safety_deposit(&true);
+    factory.set_protocol_fee(&ProtocolFee { bps: 30, rounding: FeeRounding::RoundUp });
+    let guardian = Address::generate(&e);
+    factory.set_guardian(&guardian);
+    factory.set_require_eoa(&true);
+    let secret_registry = Address::generate(&e);
+    factory.set_secret_registry(&secret_registry);
+    factory.set_keeper_reward(&25);
+    let deposit_token = Address::generate(&e);
+    factory.set_deposit_tokens(&vec![&e, deposit_token.clone()]);
+    factory.set_min_transfer(&deposit_token, &10);
+    let new_admin = Address::generate(&e);
+    factory.transfer_admin(&admin, &new_admin);
+    let (_rebate_token, rebate_token) = create_token_contract(&e, &admin);
+    factory.set_rebate(&Rebate { token: rebate_token.address.clone(), amount: 15, prompt_window: 100 });
+    let funder = Address::generate(&e);
+    _rebate_token.mint(&funder, &100);
+    factory.fund_rebate_pool(&funder, &rebate_token.address, &100);
+
+    assert_eq!(
+        factory.config(),
+        FactoryConfig {
+            admin,
+            dispute_window: 600,
+            max_parts: 8,
+            min_amount: 100,
+            clock_source: ClockSource::Sequence,
+            public_cooldown: 60,
+            ttl_bump: 100_000,
+            topic_prefix: Symbol::new(&e, "htlc_a"),
+            escrow_wasm_hash: upload_escrow_wasm(&e),
+            amount_validity: 3600,
+            reward_ramp: 1200,
+            maker_auth_cancel: true,
+            max_future: 86_400,
+            cancel_oracle: Some(BytesN::from_array(&e, &[3; 32])),
+            no_safety_deposit: true,
+            protocol_fee: ProtocolFee { bps: 30, rounding: FeeRounding::RoundUp },
+            guardian: Some(guardian),
+            require_eoa: true,
+            secret_registry: Some(secret_registry),
+            keeper_reward: 25,
+            deposit_tokens: vec![&e, deposit_token.clone()],
+            min_transfers: Map::from_array(&e, [(deposit_token, 10)]),
+            pending_admin: Some(new_admin),
+            rebate_token: Some(rebate_token.address),
+            rebate_amount: 15,
+            rebate_prompt_window: 100,
+            rebate_pool: 100,
+        }
+    );
+}
+
+#[test]
+fn test_no_safety_deposit() {
+    let Setup { e, factory, token_sac, token, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+
+    // The taker holds no safety deposit tokens at all
+    token_sac.mint(&maker, &1000);
+
+    let mut immutables = EscrowImmutables {
+        extra_safety_deposits: vec![&e, (token.address.clone(), 20)],
+        ..base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    factory.set_no_safety_deposit(&true);
+    assert_eq!(factory.required_funding(&immutables), (500, 0));
+
+    let withdrawn = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    immutables.hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let cancelled_address = factory.create_escrow(&immutables, &0, &taker);
+    let cancelled = EscrowClient::new(&e, &cancelled_address);
+    assert_eq!(token.balance(&maker), 0);
+    assert_eq!(token.balance(&cancelled_address), 500);
+    assert_eq!(withdrawn.effective_rate(), (500, 0));
+    assert_eq!(withdrawn.deposit_split(), (500, 0));
+    assert_eq!(withdrawn.caller_economics(&taker).safety_deposits, vec![&e]);
+
+    // Settling moves the principal only, even for a public caller
+    jump_time(&e, 2001);
+    let caller = Address::generate(&e);
+    withdrawn.withdraw(&secret, &caller);
+    assert_eq!(withdrawn.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&taker), 500);
+    assert_eq!(token.balance(&caller), 0);
+
+    jump_time(&e, 2000);
+    cancelled.cancel(&caller);
+    assert_eq!(cancelled.get_state(), EscrowState::Cancelled);
+    assert_eq!(token.balance(&maker), 500);
+    assert_eq!(token.balance(&caller), 0);
+    assert_eq!(safety_token.balance(&caller), 0);
+}
+
+#[test]
+fn test_cancel_on_source_cancelled() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let oracle = SigningKey::from_bytes(&[5; 32]);
+    let impostor = SigningKey::from_bytes(&[6; 32]);
+
+    // Mint tokens
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let mut immutables = EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+    let sign_for = |key: &SigningKey, factory: &Address, hashlock: &BytesN<32>| {
+        let message = source_cancelled_message(&e, factory, hashlock);
+        let mut buffer = [0; 256];
+        message.copy_into_slice(&mut buffer[..message.len() as usize]);
+        BytesN::from_array(&e, &key.sign(&buffer[..message.len() as usize]).to_bytes())
+    };
+    let sign = |key: &SigningKey, hashlock: &BytesN<32>| sign_for(key, &factory.address, hashlock);
+
+    // Escrows created without an oracle can't be cancelled early
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    let error = escrow.try_cancel_on_source_cancelled(&sign(&oracle, &immutables.hashlock));
+    assert_eq!(error.err(), Some(Ok(EscrowError::Unauthorized.into())));
+
+    factory.set_cancel_oracle(&BytesN::from_array(&e, &oracle.verifying_key().to_bytes()));
+    immutables.hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    assert_eq!(token.balance(&taker), 0);
+
+    // Attestations by another key or for another order are rejected
+    assert!(escrow
+        .try_cancel_on_source_cancelled(&sign(&impostor, &immutables.hashlock))
+        .is_err());
+    assert!(escrow
+        .try_cancel_on_source_cancelled(&sign(&oracle, &BytesN::from_array(&e, &[1; 32])))
+        .is_err());
+
+    // Nor are attestations for another factory or network
+    assert!(escrow
+        .try_cancel_on_source_cancelled(&sign_for(&oracle, &Address::generate(&e), &immutables.hashlock))
+        .is_err());
+    let signature = sign(&oracle, &immutables.hashlock);
+    let network_id = e.ledger().network_id();
+    e.ledger().set_network_id([9; 32]);
+    assert!(escrow.try_cancel_on_source_cancelled(&signature).is_err());
+    e.ledger().set_network_id(network_id.to_array());
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+
+    // Well before the cancellation window the attested escrow refunds the taker
+    let error = escrow.try_cancel(&taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+    escrow.cancel_on_source_cancelled(&sign(&oracle, &immutables.hashlock));
+    assert_eq!(escrow.get_state(), EscrowState::Cancelled);
+    assert_eq!(token.balance(&taker), 500);
+    assert_eq!(safety_token.balance(&taker), 50);
+    assert_eq!(token.balance(&escrow_address), 0);
+}
+
+#[test]
+fn test_withdraw_segments_emits_fill_events() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Order split in two segments, each locked by its own secret
+    let secret_0 = generate_secret(&e);
+    let secret_1 = generate_secret(&e);
+    let hashlock_0 = e.crypto().sha256(&secret_0).to_bytes();
+    let hashlock_1 = e.crypto().sha256(&secret_1).to_bytes();
+    let leaf_0 = merkle_leaf(&e, 0, 300, &hashlock_0);
+    let leaf_1 = merkle_leaf(&e, 1, 200, &hashlock_1);
+    let root = merkle_node(&e, &leaf_0, &leaf_1);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, root.clone(), &maker, &token.address, &safety_token.address)
+    };
+
+    let address_0 = factory.create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_1.clone()],
+        &0,
+        &hashlock_0,
+        &300,
+        &taker,
+    );
+    let address_1 = factory.create_escrow_for_segment(
+        &immutables,
+        &vec![&e, leaf_0.clone()],
+        &1,
+        &hashlock_1,
+        &200,
+        &taker,
+    );
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    EscrowClient::new(&e, &address_1).withdraw(&secret_1, &taker);
+    let (contract, topics, data) = e.events().all().last().unwrap();
+    assert_eq!(contract, address_1);
+    assert_eq!(topics, (Symbol::new(&e, "htlc"), Symbol::new(&e, "withdraw_fill"), root.clone()).into_val(&e));
+    assert_eq!(<(u32, i128, i128)>::try_from_val(&e, &data).unwrap(), (1, 200, 200));
+
+    EscrowClient::new(&e, &address_0).withdraw(&secret_0, &taker);
+    let (contract, topics, data) = e.events().all().last().unwrap();
+    assert_eq!(contract, address_0);
+    assert_eq!(topics, (Symbol::new(&e, "htlc"), Symbol::new(&e, "withdraw_fill"), root.clone()).into_val(&e));
+    assert_eq!(<(u32, i128, i128)>::try_from_val(&e, &data).unwrap(), (0, 300, 500));
+
+    // Both leaf secrets are listed for the order
+    assert_eq!(factory.revealed_secrets(&root), vec![&e, secret_1, secret_0.clone()]);
+
+    // Only an escrow of the factory can record a fill
+    let error = factory.try_record_fill(&Address::generate(&e), &root, &300, &secret_0);
+    assert_eq!(error.err(), Some(Ok(EscrowError::Unauthorized.into())));
+}
+
+#[test]
+fn test_public_withdrawal_cooldown() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let public = Address::generate(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = |hashlock| EscrowImmutables {
+        amount: AmountCalc::Flat(100),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, hashlock, &maker, &token.address, &safety_token.address)
+    };
+
+    factory.set_public_cooldown(&60);
+
+    let secrets = [generate_secret(&e), generate_secret(&e), generate_secret(&e)];
+    let escrows = secrets.clone().map(|secret| {
+        let hashlock = e.crypto().sha256(&secret).to_bytes();
+        EscrowClient::new(&e, &factory.create_escrow(&immutables(hashlock), &0, &taker))
+    });
+
+    // Advance time past public withdrawal timelock
+    jump_time(&e, 2001);
+
+    // Public callers withdraw as themselves, so the cooldown holds per caller
+    escrows[0].withdraw(&secrets[0], &public);
+    assert!(e.auths().iter().any(|(address, _)| *address == public));
+
+    // Rapid repeat by the same public caller is rejected
+    jump_time(&e, 59);
+    let error = escrows[1].try_withdraw(&secrets[1], &public);
+    assert_eq!(error.err(), Some(Ok(EscrowError::CooldownActive.into())));
+
+    // Taker isn't rate limited
+    escrows[2].withdraw(&secrets[2], &taker);
+
+    // Allowed again once the cooldown is over
+    jump_time(&e, 1);
+    escrows[1].withdraw(&secrets[1], &public);
+    assert_eq!(safety_token.balance(&public), 20);
+}
+
+#[test]
+fn test_state_changes_bump_ttl() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    factory.set_ttl_bump(&100_000);
+    assert_eq!(factory.ttl_bump(), 100_000);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    let ttl = || e.as_contract(&escrow_address, || e.storage().instance().get_ttl());
+    assert_eq!(ttl(), 100_000);
+
+    e.ledger().set_sequence_number(e.ledger().sequence() + 1000);
+    assert_eq!(ttl(), 99_000);
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(ttl(), 100_000);
+}
+
+#[test]
+fn test_factory_indexes_bump_ttl() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let hashlock_0 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let hashlock_1 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let leaf_0 = merkle_leaf(&e, 0, 300, &hashlock_0);
+    let leaf_1 = merkle_leaf(&e, 1, 200, &hashlock_1);
+    let root = merkle_node(&e, &leaf_0, &leaf_1);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    factory.set_ttl_bump(&100_000);
+
+    let immutables = base_immutables(&e, root.clone(), &maker, &token.address, &safety_token.address);
+    factory.create_escrow_for_segment(&immutables, &vec![&e, leaf_1], &0, &hashlock_0, &300, &taker);
+
+    let ttl = |key: Vec<Val>| e.as_contract(&factory.address, || e.storage().persistent().get_ttl(&key));
+    let index_key = vec![&e, Symbol::new(&e, "escrow").into_val(&e), 0u32.into_val(&e)];
+    let taker_key = vec![&e, Symbol::new(&e, "taker_escrows").into_val(&e), taker.into_val(&e)];
+    let filled_key = vec![&e, Symbol::new(&e, "filled").into_val(&e), root.into_val(&e)];
+    let segment_key = vec![&e, Symbol::new(&e, "segment").into_val(&e), root.into_val(&e), 0u32.into_val(&e)];
+
+    // Written entries live as long as the escrow record
+    assert_eq!(ttl(index_key.clone()), 100_000);
+    assert_eq!(ttl(taker_key.clone()), 100_000);
+    assert_eq!(ttl(filled_key), 100_000);
+    assert_eq!(ttl(segment_key), 100_000);
+
+    // Reading the indexes keeps them alive
+    e.ledger().set_sequence_number(e.ledger().sequence() + 1000);
+    assert_eq!(ttl(index_key.clone()), 99_000);
+    factory.public_actionable_page(&0, &10);
+    factory.escrows_of_taker(&taker);
+    assert_eq!(ttl(index_key), 100_000);
+    assert_eq!(ttl(taker_key), 100_000);
+}
+
+#[test]
+fn test_revealed_secret() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    assert_eq!(escrow.revealed_secret(), None);
+
+    // Advance time past withdrawal timelock
+    jump_time(&e, 1001);
+
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(escrow.revealed_secret(), Some(secret));
+}
+
+#[test]
+fn test_prompt_withdraw_rebate() {
+    let Setup { e, factory, token_admin, token_sac, token, safety_token_sac, safety_token, maker, taker } = setup();
+
+    let (_rebate_token, rebate_token) = create_token_contract(&e, &token_admin);
+
+    let funder = Address::generate(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+    _rebate_token.mint(&funder, &100);
+
+    let immutables = |hashlock| EscrowImmutables {
+        amount: AmountCalc::Flat(100),
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, hashlock, &maker, &token.address, &safety_token.address)
+    };
+
+    // The pool is funded before the rebate is set
+    factory.fund_rebate_pool(&funder, &rebate_token.address, &100);
+    assert_eq!(factory.rebate(), None);
+    assert_eq!(factory.rebate_pool(&rebate_token.address), 100);
+    assert_eq!(rebate_token.balance(&factory.address), 100);
+
+    // Funding nothing is rejected
+    let error = factory.try_fund_rebate_pool(&funder, &rebate_token.address, &0);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+
+    factory.set_rebate(&Rebate {
+        token: rebate_token.address.clone(),
+        amount: 15,
+        prompt_window: 100,
+    });
+
+    let secret_prompt = generate_secret(&e);
+    let secret_late = generate_secret(&e);
+    let prompt = factory.create_escrow(&immutables(e.crypto().sha256(&secret_prompt).to_bytes()), &0, &taker);
+    let late = factory.create_escrow(&immutables(e.crypto().sha256(&secret_late).to_bytes()), &0, &taker);
+
+    // Prompt withdrawal earns the rebate
+    jump_time(&e, 1099);
+    EscrowClient::new(&e, &prompt).withdraw(&secret_prompt, &taker);
+    assert_eq!(rebate_token.balance(&taker), 15);
+    assert_eq!(factory.rebate_pool(&rebate_token.address), 85);
+
+    // Late one doesn't
+    jump_time(&e, 1);
+    EscrowClient::new(&e, &late).withdraw(&secret_late, &taker);
+    assert_eq!(rebate_token.balance(&taker), 15);
+    assert_eq!(factory.rebate_pool(&rebate_token.address), 85);
+
+    // Switching the rebate token leaves the former pool to its token
+    let (_other_token, other_token) = create_token_contract(&e, &token_admin);
+    factory.set_rebate(&Rebate {
+        token: other_token.address.clone(),
+        amount: 15,
+        prompt_window: 100,
+    });
+    assert_eq!(factory.rebate_pool(&other_token.address), 0);
+    assert_eq!(factory.config().rebate_pool, 0);
+
+    let secret = generate_secret(&e);
+    let escrow = factory.create_escrow(&immutables(e.crypto().sha256(&secret).to_bytes()), &0, &taker);
+    jump_time(&e, 1000);
+    EscrowClient::new(&e, &escrow).withdraw(&secret, &taker);
+    assert_eq!(rebate_token.balance(&taker), 15);
+    assert_eq!(other_token.balance(&taker), 0);
+    assert_eq!(factory.rebate_pool(&rebate_token.address), 85);
+}
+
+#[test]
+fn test_create_escrow_zero_amount() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let current_time = e.ledger().timestamp();
+    let immutables = |amount| EscrowImmutables {
+        amount,
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Flat zero
+    let error = factory.try_create_escrow(&immutables(AmountCalc::Flat(0)), &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::AmountTooSmall.into())));
+
+    // Auction decayed to zero
+    let auction = AmountCalc::Linear(DutchAuction {
+        start_time: current_time,
+        stop_time: current_time + 1000,
+        start_amount: 500,
+        stop_amount: 0,
+    });
+    jump_time(&e, 1000);
+    let error = factory.try_create_escrow(&immutables(auction), &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::AmountTooSmall.into())));
+    assert_eq!(safety_token.balance(&taker), 100);
+}
+
+#[test]
+fn test_order_hash_of() {
+    let e = Env::default();
+
+    let factory = create_escrow_factory_contract(&e);
+    let token_admin = Address::generate(&e);
+    let (_token, token) = create_token_contract(&e, &token_admin);
+    let (_safety_token, safety_token) = create_token_contract(&e, &token_admin);
+
+    let immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &Address::generate(&e), &token.address, &safety_token.address);
+
+    // Stable across calls
+    let order_hash = factory.order_hash_of(&immutables);
+    assert_eq!(factory.order_hash_of(&immutables), order_hash);
+    assert_eq!(order_hash, e.crypto().sha256(&immutables.encode_order(&e)).to_bytes());
+
+    // Every order-defining field changes the hash
+    let mut changed = [
+        immutables.clone(),
+        immutables.clone(),
+        immutables.clone(),
+        immutables.clone(),
+        immutables.clone(),
+    ];
+    changed[0].hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    changed[1].maker = Address::generate(&e);
+    changed[2].token = safety_token.address.clone();
+    changed[3].amount = AmountCalc::Flat(501);
+    changed[4].timelocks.public_cancellation = 4001;
+    for immutables in changed {
+        assert_ne!(factory.order_hash_of(&immutables), order_hash);
+    }
+
+    // Escrow-only fields don't
+    let mut escrow_only = immutables.clone();
+    escrow_only.safety_deposit = SafetyDeposit::Flat(51);
+    assert_eq!(factory.order_hash_of(&escrow_only), order_hash);
+}
+
+#[test]
+fn test_keeper_cancel() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let keeper = Address::generate(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    factory.set_keeper_reward(&30);
+
+    let immutables = EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(100),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Not before public cancellation opens
+    jump_time(&e, 3999);
+    let error = escrow.try_keeper_cancel(&keeper);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    // The keeper gets the fixed reward, the maker its funds and the taker the rest
+    jump_time(&e, 1);
+    escrow.keeper_cancel(&keeper);
+    assert_eq!(escrow.get_state(), EscrowState::Cancelled);
+    assert_eq!(safety_token.balance(&keeper), 30);
+    assert_eq!(safety_token.balance(&taker), 70);
+    assert_eq!(token.balance(&maker), 1000);
+    assert_eq!(token.balance(&escrow_address), 0);
+
+    // Negative rewards are rejected
+    let error = factory.try_set_keeper_reward(&-1);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+}
+
+#[test]
+fn test_volume_of() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &200);
+
+    let mut escrows = vec![&e];
+    for amount in [500, 300] {
+        let secret = generate_secret(&e);
+        let immutables = EscrowImmutables {
+            amount: AmountCalc::Flat(amount),
+            ..base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address)
+        };
+        escrows.push_back((factory.create_escrow(&immutables, &0, &taker), secret));
+    }
+    assert_eq!(factory.volume_of(&token.address), (0, 0));
+
+    // Withdraw the first escrow
+    jump_time(&e, 1001);
+    let (escrow_address, secret) = escrows.get_unchecked(0);
+    EscrowClient::new(&e, &escrow_address).withdraw(&secret, &taker);
+    assert_eq!(factory.volume_of(&token.address), (500, 0));
+
+    // Cancel the second one
+    jump_time(&e, 2000);
+    let (escrow_address, _) = escrows.get_unchecked(1);
+    EscrowClient::new(&e, &escrow_address).cancel(&taker);
+    assert_eq!(factory.volume_of(&token.address), (500, 300));
+
+    // Safety deposits don't count towards volume
+    assert_eq!(factory.volume_of(&safety_token.address), (0, 0));
+}
+
+#[test]
+fn test_open_withdrawal_early() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    jump_time(&e, 10);
+    let error = escrow.try_withdraw(&secret, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    // Only the escrow's parties can open it early
+    let error = escrow.try_open_withdrawal_early(&Address::generate(&e), &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::Unauthorized.into())));
+
+    // The window opens now, the signed immutables stay as created
+    escrow.open_withdrawal_early(&maker, &taker);
+    assert_eq!(escrow.get_timelocks().withdrawal, 10);
+    assert_eq!(escrow.get_immutables(), immutables);
+    assert!(escrow.verify_immutables(&immutables));
+
+    // Public withdrawal stays locked
+    let error = escrow.try_withdraw(&secret, &Address::generate(&e));
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    assert_eq!(token.balance(&taker), 500);
+
+    // Not once settled
+    let error = escrow.try_open_withdrawal_early(&maker, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::NotActive.into())));
+}
+
+#[test]
+fn test_get_timelocks() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    assert_eq!(escrow.get_timelocks(), immutables.timelocks);
+    assert_eq!(escrow.get_timelocks(), escrow.get_immutables().timelocks);
+}
+
+#[test]
+fn test_escrow_registered_at_predicted_address() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.clone(), &maker, &token.address, &safety_token.address);
+
+    let predicted = factory.predict_address(&hashlock, &7);
+    assert_ne!(factory.predict_address(&hashlock, &0), predicted);
+    let escrow_address = factory.create_escrow(&immutables, &7, &taker);
+    assert_eq!(escrow_address, predicted);
+
+    // Funds and code both landed at the predicted address
+    assert_eq!(token.balance(&predicted), 500);
+    assert_eq!(EscrowClient::new(&e, &predicted).get_immutables(), immutables);
+
+    // The escrow is deployed from the configured wasm, so a factory pointing at a missing wasm can't create one
+    let missing_wasm = TestFactory::new(
+        &e,
+        &e.register(EscrowFactory, (Address::generate(&e), Symbol::new(&e, "htlc"), BytesN::from_array(&e, &[7; 32]))),
+    );
+    let hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let immutables = base_immutables(&e, hashlock, &maker, &token.address, &safety_token.address);
+    assert!(missing_wasm.try_create_escrow(&immutables, &0, &taker).is_err());
+}
+
+#[test]
+fn test_get_direction() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let mut immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    assert_eq!(escrow.get_direction(), EscrowDirection::Maker2Taker);
+    assert_eq!(escrow.get_direction(), escrow.get_immutables().direction);
+
+    immutables.hashlock = e.crypto().sha256(&Bytes::from_array(&e, &[7; 32])).to_bytes();
+    immutables.direction = EscrowDirection::Taker2Maker;
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    assert_eq!(escrow.get_direction(), EscrowDirection::Taker2Maker);
+}
+
+#[test]
+fn test_immutables_xdr() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let current_time = e.ledger().timestamp();
+    let immutables = EscrowImmutables {
+        second_hashlock: Some(e.crypto().sha256(&generate_secret(&e)).to_bytes()),
+        hash_algo: HashAlgo::Keccak256,
+        refund_address_hint: bytes!(&e, 0x0102),
+        amount: AmountCalc::Linear(DutchAuction {
+            start_time: current_time,
+            stop_time: current_time + 1000,
+            start_amount: 500,
+            stop_amount: 300,
+        }),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+
+    let xdr = escrow.immutables_xdr();
+    assert_eq!(EscrowImmutables::from_xdr(&e, &xdr).unwrap(), immutables);
+}
+
+#[test]
+fn test_verify_immutables() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+
+    assert!(escrow.verify_immutables(&immutables));
+
+    // The stored immutables are hashed once, at creation
+    let stored = e.as_contract(&escrow.address, || {
+        e.storage().instance().get::<_, BytesN<32>>(&Symbol::new(&e, "immutables_hash")).unwrap()
+    });
+    assert_eq!(stored, e.crypto().sha256(&immutables.encode(&e)).to_bytes());
+
+    let mut claimed = immutables.clone();
+    claimed.timelocks.cancellation = 3001;
+    assert!(!escrow.verify_immutables(&claimed));
+
+    let mut claimed = immutables.clone();
+    claimed.maker = Address::generate(&e);
+    assert!(!escrow.verify_immutables(&claimed));
+}
+
+#[test]
+fn test_create_escrow_duplicate_deposit_token() {
+    let Setup { e, factory, token_admin, token_sac, token, safety_token_sac, safety_token, maker, taker } = setup();
+
+    let (_extra_token, extra_token) = create_token_contract(&e, &token_admin);
+
+    let hashlock = e.crypto().sha256(&generate_secret(&e));
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+    _extra_token.mint(&taker, &100);
+
+    let mut immutables = EscrowImmutables {
+        extra_safety_deposits: vec![&e, (extra_token.address.clone(), 10), (safety_token.address.clone(), 20)],
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Repeating the primary deposit token
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::DuplicateDepositToken.into())));
+
+    // Repeating an extra deposit token
+    immutables.extra_safety_deposits = vec![&e, (extra_token.address.clone(), 10), (extra_token.address.clone(), 20)];
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::DuplicateDepositToken.into())));
+    assert_eq!(safety_token.balance(&taker), 100);
+    assert_eq!(extra_token.balance(&taker), 100);
+
+    // Distinct tokens are fine
+    immutables.extra_safety_deposits = vec![&e, (extra_token.address.clone(), 10)];
+    factory.create_escrow(&immutables, &0, &taker);
+}
+
+#[test]
+fn test_event_topic_prefix() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let prefix = Symbol::new(&e, "desk_b");
+    let factory = TestFactory::new(
+        &e,
+        &e.register(EscrowFactory, (Address::generate(&e), prefix.clone(), upload_escrow_wasm(&e))),
+    );
+    let token_admin = Address::generate(&e);
+    let (_token, token) = create_token_contract(&e, &token_admin);
+    let (_safety_token, safety_token) = create_token_contract(&e, &token_admin);
+
+    let maker = Address::generate(&e);
+    let taker = Address::generate(&e);
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    _token.mint(&maker, &1000);
+    _safety_token.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    assert_eq!(factory.topic_prefix(), prefix);
+
+    // Factory events
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let (contract, topics, _) = e.events().all().last().unwrap();
+    assert_eq!(contract, factory.address);
+    assert_eq!(
+        topics,
+        (prefix.clone(), Symbol::new(&e, "escrow_created"), escrow_address.clone()).into_val(&e)
+    );
+
+    // Escrow events
+    jump_time(&e, 1001);
+    EscrowClient::new(&e, &escrow_address).withdraw(&secret, &taker);
+    let (contract, topics, _) = e.events().all().last().unwrap();
+    assert_eq!(contract, escrow_address);
+    assert_eq!(topics, (prefix, Symbol::new(&e, "withdraw")).into_val(&e));
+}
+
+#[test]
+fn test_reprice_stale_amount() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    factory.set_amount_validity(&100);
+
+    let current_time = e.ledger().timestamp();
+    let dutch_auction = DutchAuction {
+        start_time: current_time,
+        stop_time: current_time + 1000,
+        start_amount: 500,
+        stop_amount: 300,
+    };
+
+    let mut escrows = vec![&e];
+    for _ in 0..2 {
+        let secret = generate_secret(&e);
+        let immutables = EscrowImmutables {
+            direction: EscrowDirection::Taker2Maker,
+            amount: AmountCalc::Linear(dutch_auction.clone()),
+            ..base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address)
+        };
+        escrows.push_back((factory.create_escrow(&immutables, &0, &taker), secret));
+    }
+    assert_eq!(token.balance(&taker), 0);
+
+    let (frozen_address, frozen_secret) = escrows.get_unchecked(0);
+    let (repriced_address, repriced_secret) = escrows.get_unchecked(1);
+    let frozen = EscrowClient::new(&e, &frozen_address);
+    let repriced = EscrowClient::new(&e, &repriced_address);
+
+    // Amount still valid
+    let error = repriced.try_reprice();
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    // Auction has ended by the time withdrawal opens
+    jump_time(&e, 1001);
+
+    // Frozen escrow pays the creation-time amount
+    frozen.withdraw(&frozen_secret, &taker);
+    assert_eq!(token.balance(&maker), 500);
+
+    // Repriced escrow pays the auction floor, the taker getting the rest back
+    repriced.reprice();
+    assert_eq!(repriced.get_resolves().amount, 300);
+    assert_eq!(token.balance(&taker), 200);
+    repriced.withdraw(&repriced_secret, &taker);
+    assert_eq!(token.balance(&maker), 800);
+    assert_eq!(token.balance(&repriced_address), 0);
+
+    // Not once settled
+    let error = repriced.try_reprice();
+    assert_eq!(error.err(), Some(Ok(EscrowError::NotActive.into())));
+}
+
+#[test]
+fn test_reprice_ignores_backward_timestamp() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&taker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    factory.set_amount_validity(&100);
+
+    let current_time = e.ledger().timestamp();
+    let immutables = EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        amount: AmountCalc::Linear(DutchAuction {
+            start_time: current_time,
+            stop_time: current_time + 1000,
+            start_amount: 500,
+            stop_amount: 300,
+        }),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+
+    // Reprice at the auction midpoint
+    jump_time(&e, 500);
+    escrow.reprice();
+    assert_eq!(escrow.implied_rate(), (400, 400));
+
+    // Clock moving backward doesn't raise the quote
+    e.ledger().set_timestamp(current_time + 200);
+    assert_eq!(escrow.implied_rate(), (400, 400));
+
+    // Moving forward again it resumes
+    e.ledger().set_timestamp(current_time + 750);
+    assert_eq!(escrow.implied_rate(), (400, 350));
+}
+
+#[test]
+fn test_create_escrow_taker_auth_scoped_to_immutables() {
+    let e = Env::default();
+
+    let factory = create_escrow_factory_contract(&e);
+    let token_admin = Address::generate(&e);
+    let (_token, token) = create_token_contract(&e, &token_admin);
+    let (_safety_token, safety_token) = create_token_contract(&e, &token_admin);
+
+    let maker = Address::generate(&e);
+    let taker = Address::generate(&e);
+    let hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    // Mint tokens
+    e.mock_all_auths();
+    _token.mint(&taker, &1000);
+    _safety_token.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        ..base_immutables(&e, hashlock.clone(), &maker, &token.address, &safety_token.address)
+    };
+    let escrow_address = factory.predict_address(&hashlock, &0);
+    // Bind up front, as binding on creation would use up the mocked auths
+    factory.bind(&hashlock, 0);
+    let transfers = [
+        MockAuthInvoke {
+            contract: &token.address,
+            fn_name: "transfer",
+            args: (taker.clone(), escrow_address.clone(), 500_i128).into_val(&e),
+            sub_invokes: &[],
+        },
+        MockAuthInvoke {
+            contract: &safety_token.address,
+            fn_name: "transfer",
+            args: (taker.clone(), escrow_address.clone(), 50_i128).into_val(&e),
+            sub_invokes: &[],
+        },
+    ];
+
+    // Taker authorized funding other immutables
+    let mut other = immutables.clone();
+    other.amount = AmountCalc::Flat(1);
+    e.mock_auths(&[MockAuth {
+        address: &taker,
+        invoke: &MockAuthInvoke {
+            contract: &factory.address,
+            fn_name: "create_escrow",
+            args: (other,).into_val(&e),
+            sub_invokes: &transfers,
+        },
+    }]);
+    assert!(factory.client.try_create_escrow(&immutables, &0, &taker).is_err());
+    assert_eq!(token.balance(&taker), 1000);
+
+    // Taker authorized funding these immutables
+    e.mock_auths(&[MockAuth {
+        address: &taker,
+        invoke: &MockAuthInvoke {
+            contract: &factory.address,
+            fn_name: "create_escrow",
+            args: (immutables.clone(),).into_val(&e),
+            sub_invokes: &transfers,
+        },
+    }]);
+    assert_eq!(factory.client.create_escrow(&immutables, &0, &taker), escrow_address);
+    assert_eq!(token.balance(&escrow_address), 500);
+}
+
+#[test]
+fn test_create_escrow_maker_auth_scoped_to_immutables() {
+    let e = Env::default();
+
+    let factory = create_escrow_factory_contract(&e);
+    let token_admin = Address::generate(&e);
+    let (_token, token) = create_token_contract(&e, &token_admin);
+    let (_safety_token, safety_token) = create_token_contract(&e, &token_admin);
+
+    let maker = Address::generate(&e);
+    let taker = Address::generate(&e);
+    let hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    // Mint tokens
+    e.mock_all_auths();
+    _token.mint(&maker, &1000);
+    _safety_token.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.clone(), &maker, &token.address, &safety_token.address);
+    let escrow_address = factory.predict_address(&hashlock, &0);
+    // Bind up front, as binding on creation would use up the mocked auths
+    factory.bind(&hashlock, 0);
+    let principal = [MockAuthInvoke {
+        contract: &token.address,
+        fn_name: "transfer",
+        args: (maker.clone(), escrow_address.clone(), 500_i128).into_val(&e),
+        sub_invokes: &[],
+    }];
+    let deposit = [MockAuthInvoke {
+        contract: &safety_token.address,
+        fn_name: "transfer",
+        args: (taker.clone(), escrow_address.clone(), 50_i128).into_val(&e),
+        sub_invokes: &[],
+    }];
+    let taker_invoke = MockAuthInvoke {
+        contract: &factory.address,
+        fn_name: "create_escrow",
+        args: (immutables.clone(), 0_u64, taker.clone()).into_val(&e),
+        sub_invokes: &deposit,
+    };
+
+    // Maker authorized funding other immutables, which the taker can't fund from
+    let mut other = immutables.clone();
+    other.timelocks.withdrawal = 10;
+    e.mock_auths(&[
+        MockAuth {
+            address: &maker,
+            invoke: &MockAuthInvoke {
+                contract: &factory.address,
+                fn_name: "create_escrow",
+                args: (other,).into_val(&e),
+                sub_invokes: &principal,
+            },
+        },
+        MockAuth { address: &taker, invoke: &taker_invoke },
+    ]);
+    assert!(factory.client.try_create_escrow(&immutables, &0, &taker).is_err());
+    assert_eq!(token.balance(&maker), 1000);
+    assert_eq!(safety_token.balance(&taker), 100);
+
+    // Maker authorized funding these immutables
+    e.mock_auths(&[
+        MockAuth {
+            address: &maker,
+            invoke: &MockAuthInvoke {
+                contract: &factory.address,
+                fn_name: "create_escrow",
+                args: (immutables.clone(),).into_val(&e),
+                sub_invokes: &principal,
+            },
+        },
+        MockAuth { address: &taker, invoke: &taker_invoke },
+    ]);
+    assert_eq!(factory.client.create_escrow(&immutables, &0, &taker), escrow_address);
+    assert_eq!(token.balance(&escrow_address), 500);
+    assert_eq!(safety_token.balance(&escrow_address), 50);
+}
+
+#[test]
+fn test_create_escrow_deposit_token_allowlist() {
+    let Setup { e, factory, token_admin, token_sac, token, safety_token_sac, safety_token, maker, taker } = setup();
+
+    let (_stable_token, stable_token) = create_token_contract(&e, &token_admin);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+    _stable_token.mint(&taker, &100);
+
+    let mut immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address);
+
+    // Empty allowlist allows any token
+    assert_eq!(factory.deposit_tokens(), vec![&e]);
+    factory.create_escrow(&immutables, &0, &taker);
+
+    factory.set_deposit_tokens(&vec![&e, stable_token.address.clone()]);
+
+    // Disallowed primary deposit token
+    immutables.hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::DepositTokenNotAllowed.into())));
+
+    // Disallowed extra deposit token
+    immutables.safety_deposit_token = stable_token.address.clone();
+    immutables.extra_safety_deposits = vec![&e, (safety_token.address.clone(), 10)];
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::DepositTokenNotAllowed.into())));
+
+    // Allowed deposit token
+    immutables.extra_safety_deposits = vec![&e];
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    assert_eq!(stable_token.balance(&escrow_address), 50);
+}
+
+#[test]
+fn test_public_actionable() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &2000);
+    safety_token_sac.mint(&taker, &200);
+
+    // Escrows created 4001, 2500, 1500 and 0 seconds before the check
+    let mut escrows = vec![&e];
+    for gap in [0, 1501, 1000, 1500] {
+        jump_time(&e, gap);
+        let secret = generate_secret(&e);
+        let immutables = base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address);
+        escrows.push_back((factory.create_escrow(&immutables, &0, &taker), secret));
+    }
+    assert_eq!(factory.escrow_count(), 4);
+
+    // Settle the one in the taker's withdrawal window
+    let (withdrawn, secret) = escrows.get_unchecked(2);
+    EscrowClient::new(&e, &withdrawn).withdraw(&secret, &taker);
+
+    // Public cancellation and public withdrawal are open for the two oldest
+    let (cancellable, _) = escrows.get_unchecked(0);
+    let (withdrawable, _) = escrows.get_unchecked(1);
+    assert_eq!(factory.public_actionable(), vec![&e, cancellable.clone(), withdrawable.clone()]);
+
+    // Paging through
+    assert_eq!(factory.public_actionable_page(&0, &1), vec![&e, cancellable]);
+    assert_eq!(factory.public_actionable_page(&1, &3), vec![&e, withdrawable]);
+    assert_eq!(factory.public_actionable_page(&2, &10), vec![&e]);
+    assert_eq!(factory.public_actionable_page(&10, &10), vec![&e]);
+
+    let error = factory.try_public_actionable_page(&0, &(MAX_BATCH_QUERY + 1));
+    assert_eq!(error.err(), Some(Ok(EscrowError::BatchTooLarge.into())));
+}
+
+#[test]
+fn test_deposit_split() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    token_sac.mint(&taker, &100);
+    safety_token_sac.mint(&taker, &100);
+
+    // Deposit in the escrow token, plus an extra one in another token
+    let immutables = EscrowImmutables {
+        extra_safety_deposits: vec![&e, (safety_token.address.clone(), 20)],
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    let (amount, deposit) = escrow.deposit_split();
+    assert_eq!((amount, deposit), (500, 50));
+    assert_eq!(amount + deposit, token.balance(&escrow_address));
+
+    // Nothing left once withdrawn
+    jump_time(&e, 1001);
+    escrow.withdraw(&secret, &taker);
+    let (amount, deposit) = escrow.deposit_split();
+    assert_eq!((amount, deposit), (0, 0));
+    assert_eq!(amount + deposit, token.balance(&escrow_address));
+}
+
+#[test]
+fn test_public_reward_scales_with_delay() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let keeper = Address::generate(&e);
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    factory.set_reward_ramp(&1000);
+
+    let immutables = EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(100),
+        timelocks: TimeLocks {
+            withdrawal: 1000,
+            public_withdrawal: 2000,
+            cancellation: 4000,
+            public_cancellation: 5000,
+        },
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    let created = e.ledger().timestamp();
+
+    // Growing through the public withdrawal window, capped at the deposit
+    assert_eq!(escrow.reward_for(&(created + 1000)), 0);
+    assert_eq!(escrow.reward_for(&(created + 2000)), 0);
+    assert_eq!(escrow.reward_for(&(created + 2250)), 25);
+    assert_eq!(escrow.reward_for(&(created + 2500)), 50);
+    assert_eq!(escrow.reward_for(&(created + 3000)), 100);
+    assert_eq!(escrow.reward_for(&(created + 3999)), 100);
+
+    // Growing again from the opening of public cancellation
+    assert_eq!(escrow.reward_for(&(created + 5000)), 0);
+    assert_eq!(escrow.reward_for(&(created + 5750)), 75);
+    assert_eq!(escrow.reward_for(&(created + 7000)), 100);
+
+    // Keeper withdrawing 250 seconds into the public window
+    jump_time(&e, 2250);
+    escrow.withdraw(&secret, &keeper);
+    assert_eq!(safety_token.balance(&keeper), 25);
+    assert_eq!(safety_token.balance(&taker), 75);
+    assert_eq!(safety_token.balance(&escrow_address), 0);
+    assert_eq!(token.balance(&taker), 500);
+}
+
+#[test]
+fn test_public_cancel_bounty_grows() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &200);
+
+    factory.set_reward_ramp(&1000);
+
+    let immutables = |hashlock| EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(100),
+        timelocks: TimeLocks {
+            withdrawal: 1000,
+            public_withdrawal: 2000,
+            cancellation: 4000,
+            public_cancellation: 5000,
+        },
+        ..base_immutables(&e, hashlock, &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_a = EscrowClient::new(&e, &factory.create_escrow(&immutables(e.crypto().sha256(&generate_secret(&e)).to_bytes()), &0, &taker));
+    let escrow_b = EscrowClient::new(&e, &factory.create_escrow(&immutables(e.crypto().sha256(&generate_secret(&e)).to_bytes()), &0, &taker));
+    let keeper_a = Address::generate(&e);
+    let keeper_b = Address::generate(&e);
+
+    // Cancelling soon after public cancellation opens earns a small bounty
+    jump_time(&e, 5200);
+    escrow_a.cancel(&keeper_a);
+    assert_eq!(safety_token.balance(&keeper_a), 20);
+
+    // An escrow left abandoned longer pays a larger one
+    jump_time(&e, 400);
+    escrow_b.cancel(&keeper_b);
+    assert_eq!(safety_token.balance(&keeper_b), 60);
+
+    // The taker gets the rest and the maker its funds back
+    assert_eq!(safety_token.balance(&taker), 120);
+    assert_eq!(token.balance(&maker), 1000);
+}
+
+#[test]
+fn test_withdraw_by_caller_without_balance() {
+    let Setup { e, factory, token_sac, token, maker, taker, .. } = setup();
+
+    let keeper = Address::generate(&e);
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens, the deposit shares the escrow token
+    token_sac.mint(&maker, &1000);
+    token_sac.mint(&taker, &50);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+
+    // Neither party nor keeper holds anything, the escrow funds every transfer
+    assert_eq!(token.balance(&taker), 0);
+    assert_eq!(token.balance(&keeper), 0);
+    jump_time(&e, 2001);
+    escrow.withdraw(&secret, &keeper);
+
+    assert_eq!(token.balance(&taker), 500);
+    assert_eq!(token.balance(&keeper), 50);
+    assert_eq!(token.balance(&escrow_address), 0);
+}
+
+#[test]
+fn test_withdraw_requires_counterparty_funded() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &2000);
+    safety_token_sac.mint(&taker, &200);
+
+    // Escrow with its counterparty, plus one whose counterparty gets cancelled
+    let mut escrows = vec![&e];
+    for (withdrawal, public_withdrawal, cancellation) in [
+        (1000, 2000, 3000),
+        (1000, 2000, 3000),
+        (1000, 2000, 3000),
+        (300, 400, 500),
+    ] {
+        let secret = generate_secret(&e);
+        let immutables = EscrowImmutables {
+            timelocks: TimeLocks {
+                withdrawal,
+                public_withdrawal,
+                cancellation,
+                public_cancellation: 4000,
+            },
+            ..base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address)
+        };
+        escrows.push_back((factory.create_escrow(&immutables, &0, &taker), secret));
+    }
+    let (funded_address, funded_secret) = escrows.get_unchecked(0);
+    let (counterparty_address, _) = escrows.get_unchecked(1);
+    let (unfunded_address, unfunded_secret) = escrows.get_unchecked(2);
+    let (cancelled_address, _) = escrows.get_unchecked(3);
+
+    let funded = EscrowClient::new(&e, &funded_address);
+    funded.set_counterparty(&Some(counterparty_address.clone()), &true);
+    assert_eq!(funded.counterparty(), Some(counterparty_address));
+    assert!(funded.require_counterparty_funded());
+
+    let unfunded = EscrowClient::new(&e, &unfunded_address);
+    unfunded.set_counterparty(&Some(cancelled_address.clone()), &true);
+    jump_time(&e, 1001);
+    EscrowClient::new(&e, &cancelled_address).cancel(&taker);
+
+    // Counterparty active and funded
+    funded.withdraw(&funded_secret, &taker);
+    assert_eq!(funded.get_state(), EscrowState::Withdrawn);
+
+    // Counterparty refunded
+    let error = unfunded.try_withdraw(&unfunded_secret, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::CounterpartyNotFunded.into())));
+
+    // Unless the taker waives the check
+    unfunded.set_counterparty(&Some(cancelled_address), &false);
+    assert_eq!(e.auths()[0].0, taker);
+    unfunded.withdraw(&unfunded_secret, &taker);
+    assert_eq!(unfunded.get_state(), EscrowState::Withdrawn);
+
+    // The maker is the payee of a taker funded escrow, so the check is theirs to set
+    token_sac.mint(&taker, &1000);
+    let immutables = EscrowImmutables {
+        direction: EscrowDirection::Taker2Maker,
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+    let reverse = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    reverse.set_counterparty(&Some(funded_address), &true);
+    assert_eq!(e.auths()[0].0, maker);
+}
+
+#[test]
+fn test_reentrant_cancel_during_withdraw() {
+    let Setup { e, factory, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let token = ReentrantTokenClient::new(&e, &e.register(ReentrantToken, ()));
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    token.arm(&escrow_address, &escrow_address);
+
+    // The host rejects the token calling back into the escrow, failing the
+    // withdrawal as a whole rather than with any escrow error
+    jump_time(&e, 1001);
+    let error = escrow.try_withdraw(&secret, &taker);
+    assert_eq!(
+        error.err(),
+        Some(Ok(soroban_sdk::Error::from_type_and_code(ScErrorType::Context, ScErrorCode::InvalidAction)))
+    );
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+    assert_eq!(safety_token.balance(&escrow_address), 50);
+}
+
+#[test]
+fn test_sibling_cancel_during_withdraw() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let reentrant_token = ReentrantTokenClient::new(&e, &e.register(ReentrantToken, ()));
+
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    // Two escrows locked by the same hashlock, the sibling in a well-behaved token and
+    // created first so its public cancellation opens while the other is still withdrawable
+    let sibling_immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address);
+    let sibling = EscrowClient::new(&e, &factory.create_escrow(&sibling_immutables, &1, &taker));
+    jump_time(&e, 2000);
+    let immutables = base_immutables(&e, hashlock.to_bytes(), &maker, &reentrant_token.address, &safety_token.address);
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    reentrant_token.arm(&escrow.address, &sibling.address);
+
+    // The token cancels the sibling mid-withdrawal, before the secret is revealed,
+    // which the settlement marker of the hashlock blocks
+    jump_time(&e, 2001);
+    let error = escrow.try_withdraw(&secret, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::SettlementInProgress.into())));
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+    assert_eq!(sibling.get_state(), EscrowState::Active);
+    assert_eq!(token.balance(&sibling.address), 500);
+
+    // Once disarmed the withdrawal goes through and the sibling can't be cancelled
+    reentrant_token.arm(&Address::generate(&e), &sibling.address);
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(escrow.get_state(), EscrowState::Withdrawn);
+    let error = sibling.try_cancel(&reentrant_token.address);
+    assert_eq!(error.err(), Some(Ok(EscrowError::SecretRevealed.into())));
+}
+
+#[test]
+fn test_reentrant_cancel_during_cancel() {
+    let Setup { e, factory, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let token = ReentrantTokenClient::new(&e, &e.register(ReentrantToken, ()));
+
+    // Mint tokens
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    token.arm(&escrow_address, &escrow_address);
+
+    // Refunding calls the token, which tries to cancel the settling escrow again.
+    // The re-entry is rejected and the whole cancel with it, leaving no escrow
+    // stuck settling
+    jump_time(&e, 3001);
+    let error = escrow.try_cancel(&taker);
+    assert_eq!(
+        error.err(),
+        Some(Ok(soroban_sdk::Error::from_type_and_code(ScErrorType::Context, ScErrorCode::InvalidAction)))
+    );
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+    assert_eq!(safety_token.balance(&escrow_address), 50);
+}
+
+#[test]
+fn test_mark_stuck_frozen_token() {
+    let Setup { e, factory, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let token = FreezableTokenClient::new(&e, &e.register(FreezableToken, ()));
+
+    // Mint tokens
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    assert!(!escrow.mark_stuck(&taker));
+    assert_eq!(escrow.stuck_since(), None);
+
+    // Cancel fails while the escrow's balance is frozen
+    jump_time(&e, 3001);
+    token.freeze(&escrow_address, &true);
+    assert!(escrow.try_cancel(&taker).is_err());
+    assert!(escrow.mark_stuck(&taker));
+    let stuck_since = e.ledger().timestamp();
+    assert_eq!(escrow.stuck_since(), Some(stuck_since));
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+
+    // Marking again keeps when it got stuck
+    jump_time(&e, 100);
+    assert!(escrow.mark_stuck(&taker));
+    assert_eq!(escrow.stuck_since(), Some(stuck_since));
+
+    // Once unfrozen the retried cancel goes through
+    token.freeze(&escrow_address, &false);
+    escrow.cancel(&taker);
+    assert_eq!(escrow.get_state(), EscrowState::Cancelled);
+    assert_eq!(escrow.stuck_since(), None);
+    assert_eq!(safety_token.balance(&taker), 100);
+}
+
+#[test]
+fn test_create_escrow_require_eoa() {
+    let Setup { e, factory, .. } = setup();
+
+    let token = FreezableTokenClient::new(&e, &e.register(FreezableToken, ()));
+
+    let account = |strkey| Address::from_string(&String::from_str(&e, strkey));
+    let maker = account("GAAQCAIBAEAQCAIBAEAQCAIBAEAQCAIBAEAQCAIBAEAQCAIBAEAQDZ7H");
+    let taker = account("GABAEAQCAIBAEAQCAIBAEAQCAIBAEAQCAIBAEAQCAIBAEAQCAIBAEJXA");
+    let contract_party = Address::generate(&e);
+
+    // Account and contract addresses told apart
+    assert!(!is_contract_address(&maker));
+    assert!(!is_contract_address(&taker));
+    assert!(is_contract_address(&contract_party));
+    assert!(is_contract_address(&factory.address));
+
+    let immutables = |maker: &Address| EscrowImmutables {
+        timelocks: DEFAULT_TIMELOCKS,
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), maker, &token.address, &token.address)
+    };
+
+    // Contract parties are allowed by default
+    factory.create_escrow(&immutables(&contract_party), &0, &Address::generate(&e));
+
+    factory.set_require_eoa(&true);
+
+    // Rejected as maker, taker or backup taker once required
+    let error = factory.try_create_escrow(&immutables(&contract_party), &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::ContractParty.into())));
+    let error = factory.try_create_escrow(&immutables(&maker), &0, &contract_party);
+    assert_eq!(error.err(), Some(Ok(EscrowError::ContractParty.into())));
+    let mut with_backup = immutables(&maker);
+    with_backup.backup_taker = Some(contract_party.clone());
+    with_backup.backup_withdrawal = 1800;
+    let error = factory.try_create_escrow(&with_backup, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::ContractParty.into())));
+
+    // Accounts on both sides go through
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables(&maker), &0, &taker));
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+}
+
+#[test]
+fn test_guardian_cancel() {
+    let Setup { e, factory, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let token = FreezableTokenClient::new(&e, &e.register(FreezableToken, ()));
+
+    let guardian = Address::generate(&e);
+
+    // Mint tokens
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    token.freeze(&escrow_address, &true);
+
+    // No guardian configured
+    jump_time(&e, 3999 + GUARDIAN_GRACE);
+    let error = factory.try_guardian_cancel(&escrow_address);
+    assert_eq!(error.err(), Some(Ok(EscrowError::Unauthorized.into())));
+
+    // The escrow only answers its factory
+    e.set_auths(&[]);
+    let error = escrow.try_force_cancel();
+    assert!(error.is_err());
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+    e.mock_all_auths();
+
+    // Only escrows of the factory
+    factory.set_guardian(&guardian);
+    let error = factory.try_guardian_cancel(&Address::generate(&e));
+    assert_eq!(error.err(), Some(Ok(EscrowError::Unauthorized.into())));
+
+    // Not before the grace period past public cancellation is over
+    let error = factory.try_guardian_cancel(&escrow_address);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TooEarly.into())));
+
+    // The guardian recovers the deposit, the frozen principal staying put
+    jump_time(&e, 1);
+    assert!(!factory.guardian_cancel(&escrow_address));
+    assert_eq!(e.auths()[0].0, guardian);
+    assert_eq!(escrow.get_state(), EscrowState::Cancelled);
+    assert_eq!(safety_token.balance(&taker), 100);
+    assert_eq!(safety_token.balance(&escrow_address), 0);
+
+    // A settled escrow can't be force-cancelled again
+    let error = factory.try_guardian_cancel(&escrow_address);
+    assert_eq!(error.err(), Some(Ok(EscrowError::NotActive.into())));
+}
+
+#[test]
+fn test_guardian_cancel_revealed() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let guardian = Address::generate(&e);
+    factory.set_guardian(&guardian);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let secret = generate_secret(&e);
+    let immutables = base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address);
+    let revealing = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &1, &taker));
+
+    // The secret is revealed by the other escrow of the hashlock
+    jump_time(&e, 1001);
+    revealing.withdraw(&secret, &taker);
+
+    // Which keeps the guardian from refunding the maker
+    jump_time(&e, 2999 + GUARDIAN_GRACE);
+    let error = factory.try_guardian_cancel(&escrow.address);
+    assert_eq!(error.err(), Some(Ok(EscrowError::SecretRevealed.into())));
+    assert_eq!(escrow.get_state(), EscrowState::Active);
+
+    escrow.withdraw(&secret, &taker);
+    assert_eq!(token.balance(&taker), 1000);
+}
+
+#[test]
+fn test_next_action_time() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address);
+
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+    let created = e.ledger().timestamp();
+
+    assert_eq!(escrow.next_action_time(), created + 1000);
+
+    // Right at a boundary the next one is ahead
+    jump_time(&e, 1000);
+    assert_eq!(escrow.next_action_time(), created + 2000);
+
+    jump_time(&e, 1500);
+    assert_eq!(escrow.next_action_time(), created + 3000);
+
+    jump_time(&e, 1000);
+    assert_eq!(escrow.next_action_time(), created + 4000);
+
+    // Every window open
+    jump_time(&e, 500);
+    assert_eq!(escrow.next_action_time(), 0);
+}
+
+#[test]
+fn test_cancel_maker_auth_mode() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1500);
+    safety_token_sac.mint(&taker, &150);
+
+    // Auth-free, auth-required, and auth-required past the private window
+    let mut escrows = vec![&e];
+    for maker_auth_cancel in [false, true, true] {
+        factory.set_maker_auth_cancel(&maker_auth_cancel);
+        let immutables = base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address);
+        escrows.push_back(factory.create_escrow(&immutables, &0, &taker));
+    }
+    let authorized = |e: &Env, address: &Address| e.auths().iter().any(|(a, _)| a == address);
+
+    // Private cancellation window
+    jump_time(&e, 3001);
+
+    EscrowClient::new(&e, &escrows.get_unchecked(0)).cancel(&taker);
+    assert!(authorized(&e, &taker));
+    assert!(!authorized(&e, &maker));
+
+    EscrowClient::new(&e, &escrows.get_unchecked(1)).cancel(&taker);
+    assert!(authorized(&e, &taker));
+    assert!(authorized(&e, &maker));
+
+    // Public cancellation window
+    jump_time(&e, 1000);
+    EscrowClient::new(&e, &escrows.get_unchecked(2)).cancel(&taker);
+    assert!(authorized(&e, &taker));
+    assert!(!authorized(&e, &maker));
+}
+
+#[test]
+fn test_create_escrow_maker_receiver() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let maker_receiver = Address::generate(&e);
+    let hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        maker_receiver: Some(maker_receiver.clone()),
+        ..base_immutables(&e, hashlock.clone(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_address = factory.create_escrow(&immutables, &0, &taker);
+
+    // Emitted with the created event
+    let (_, _, data) = e.events().all().last().unwrap();
+    let data = <(BytesN<32>, i128, u64, Option<Address>)>::try_from_val(&e, &data).unwrap();
+    assert_eq!(data, (hashlock, 500, e.ledger().timestamp(), Some(maker_receiver.clone())));
+
+    // Stored with the immutables, and part of their encoding
+    let escrow = EscrowClient::new(&e, &escrow_address);
+    assert_eq!(escrow.get_immutables().maker_receiver, Some(maker_receiver));
+    assert_eq!(EscrowImmutables::decode(&e, &immutables.encode(&e)), immutables);
+}
+
+#[test]
+fn test_derive_hashlock() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    for algo in [HashAlgo::Sha256, HashAlgo::Keccak256] {
+        let secret = generate_secret(&e);
+        let hashlock = derive_hashlock(&e, &secret, &algo);
+        assert_eq!(factory.compute_hashlock(&secret, &algo), hashlock);
+
+        let immutables = EscrowImmutables {
+            hash_algo: algo,
+            ..base_immutables(&e, hashlock.clone(), &maker, &token.address, &safety_token.address)
+        };
+
+        // The escrow accepts the secret of the derived hashlock
+        let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+        jump_time(&e, 1001);
+        escrow.withdraw(&secret, &taker);
+        assert!(factory.is_revealed(&hashlock));
+    }
+    assert_eq!(token.balance(&taker), 1000);
+}
+
+#[test]
+fn test_create_escrow_zero_hashlock() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let mut immutables = base_immutables(&e, BytesN::from_array(&e, &[0; 32]), &maker, &token.address, &safety_token.address);
+
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::ZeroHashlock.into())));
+
+    // Whichever algorithm and hashlock is left unset
+    immutables.hash_algo = HashAlgo::Keccak256;
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::ZeroHashlock.into())));
+    immutables.hashlock = e.crypto().keccak256(&generate_secret(&e)).to_bytes();
+    immutables.second_hashlock = Some(BytesN::from_array(&e, &[0; 32]));
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::ZeroHashlock.into())));
+    assert_eq!(token.balance(&maker), 1000);
+}
+
+#[test]
+fn test_create_escrow_public_withdrawal_overlaps_cancellation() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let mut immutables = EscrowImmutables {
+        timelocks: TimeLocks {
+            withdrawal: 1000,
+            public_withdrawal: 3000,
+            cancellation: 3000,
+            public_cancellation: 4000,
+        },
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Public withdrawal opening with cancellation leaves it no window
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::WithdrawalOverlapsCancellation.into())));
+
+    // Nor may it open after cancellation
+    immutables.timelocks.public_withdrawal = 3500;
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::WithdrawalOverlapsCancellation.into())));
+    assert_eq!(token.balance(&maker), 1000);
+
+    immutables.timelocks.public_withdrawal = 2999;
+    factory.create_escrow(&immutables, &0, &taker);
+    assert_eq!(token.balance(&maker), 500);
+}
+
+#[test]
+fn test_create_escrow_zero_timelocks() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        timelocks: TimeLocks {
+            withdrawal: 0,
+            public_withdrawal: 0,
+            cancellation: 0,
+            public_cancellation: 0,
+        },
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Every window opening at once leaves withdraw and cancel racing
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidImmutables.into())));
+    assert_eq!(token.balance(&maker), 1000);
+}
+
+#[test]
+fn test_has_public_withdrawal() {
+    let timelocks = |public_withdrawal, cancellation| TimeLocks {
+        withdrawal: 1000,
+        public_withdrawal,
+        cancellation,
+        public_cancellation: 4000,
+    };
+
+    assert!(timelocks(2000, 3000).has_public_withdrawal());
+    assert!(timelocks(2999, 3000).has_public_withdrawal());
+    // Public withdrawal opening with or after cancellation has no window
+    assert!(!timelocks(3000, 3000).has_public_withdrawal());
+    assert!(!timelocks(3500, 3000).has_public_withdrawal());
+}
+
+#[test]
+fn test_create_escrow_max_future() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    factory.set_max_future(&4000);
+
+    let mut immutables = EscrowImmutables {
+        timelocks: TimeLocks {
+            withdrawal: 1000,
+            public_withdrawal: 2000,
+            cancellation: 3000,
+            public_cancellation: 4001,
+        },
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    // Above the bound
+    let error = factory.try_create_escrow(&immutables, &0, &taker);
+    assert_eq!(error.err(), Some(Ok(EscrowError::TimelockTooFar.into())));
+    assert_eq!(token.balance(&maker), 1000);
+
+    // At the bound
+    immutables.timelocks.public_cancellation = 4000;
+    factory.create_escrow(&immutables, &0, &taker);
+
+    // Below the bound
+    immutables.hashlock = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    immutables.amount = AmountCalc::Flat(400);
+    immutables.timelocks.public_cancellation = 3999;
+    factory.create_escrow(&immutables, &0, &taker);
+    assert_eq!(token.balance(&maker), 100);
+}
+
+#[test]
+fn test_split_escrow() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, .. } = setup();
+
+    let taker_a = Address::generate(&e);
+    let taker_b = Address::generate(&e);
+    let secret = generate_secret(&e);
+    let hashlock = e.crypto().sha256(&secret);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker_a, &100);
+
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Flat(1000),
+        ..base_immutables(&e, hashlock.to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+
+    let escrow_a = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker_a));
+    assert_eq!(escrow_a.address, factory.predict_address(&hashlock.to_bytes(), &0));
+
+    // Both escrows must keep something
+    let error = escrow_a.try_split_escrow(&1000, &taker_b, &1);
+    assert_eq!(error.err(), Some(Ok(EscrowError::InvalidAmount.into())));
+
+    // The split can't reuse the nonce of the escrow it splits
+    let error = escrow_a.try_split_escrow(&400, &taker_b, &0);
+    assert_eq!(error.err(), Some(Ok(EscrowError::AlreadyTaken.into())));
+
+    factory.bind(&immutables.hashlock, 1);
+    let escrow_b = EscrowClient::new(&e, &escrow_a.split_escrow(&400, &taker_b, &1));
+    assert_eq!(escrow_b.address, factory.predict_address(&hashlock.to_bytes(), &1));
+    assert_eq!(escrow_a.get_resolves().amount, 600);
+    assert_eq!(escrow_b.get_resolves().amount, 400);
+    assert_eq!(token.balance(&escrow_a.address), 600);
+    assert_eq!(token.balance(&escrow_b.address), 400);
+    assert_eq!(factory.escrows_of_taker(&taker_b), vec![&e, escrow_b.address.clone()]);
+
+    // Each taker withdraws its part with the order secret
+    jump_time(&e, 1001);
+    escrow_a.withdraw(&secret, &taker_a);
+    escrow_b.withdraw(&secret, &taker_b);
+    assert_eq!(token.balance(&taker_a), 600);
+    assert_eq!(token.balance(&taker_b), 400);
+    assert_eq!(safety_token.balance(&taker_a), 100);
+}
+
+#[test]
+fn test_reprice_split_escrow() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, .. } = setup();
+
+    let taker_a = Address::generate(&e);
+    let taker_b = Address::generate(&e);
+    let secret = generate_secret(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker_a, &100);
+
+    factory.set_amount_validity(&100);
+
+    let current_time = e.ledger().timestamp();
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Linear(DutchAuction {
+            start_time: current_time,
+            stop_time: current_time + 1000,
+            start_amount: 1000,
+            stop_amount: 500,
+        }),
+        ..base_immutables(&e, e.crypto().sha256(&secret).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+    let escrow_a = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker_a));
+    factory.bind(&immutables.hashlock, 1);
+    let escrow_b = EscrowClient::new(&e, &escrow_a.split_escrow(&400, &taker_b, &1));
+    assert_eq!(token.balance(&maker), 0);
+
+    // Each part reprices to its share of the order at the auction floor
+    jump_time(&e, 1001);
+    assert_eq!(escrow_a.implied_rate(), (600, 300));
+    assert_eq!(escrow_b.implied_rate(), (400, 200));
+    escrow_a.reprice();
+    escrow_b.reprice();
+    assert_eq!(escrow_a.get_resolves().amount, 300);
+    assert_eq!(escrow_b.get_resolves().amount, 200);
+    assert_eq!(token.balance(&maker), 500);
+
+    // Together the parts pay what the whole order would
+    escrow_a.withdraw(&secret, &taker_a);
+    escrow_b.withdraw(&secret, &taker_b);
+    assert_eq!(token.balance(&taker_a), 300);
+    assert_eq!(token.balance(&taker_b), 200);
+}
+
+#[test]
+fn test_split_segment_escrow() {
+    let Setup { e, factory, token_admin, token_sac, token, safety_token_sac, safety_token, maker, .. } = setup();
+
+    let (_rebate_token, rebate_token) = create_token_contract(&e, &token_admin);
+
+    let taker_a = Address::generate(&e);
+    let taker_b = Address::generate(&e);
+    let funder = Address::generate(&e);
+
+    // Order split in two segments, each locked by its own secret
+    let secret_0 = generate_secret(&e);
+    let hashlock_0 = e.crypto().sha256(&secret_0).to_bytes();
+    let hashlock_1 = e.crypto().sha256(&generate_secret(&e)).to_bytes();
+    let leaf_0 = merkle_leaf(&e, 0, 300, &hashlock_0);
+    let leaf_1 = merkle_leaf(&e, 1, 200, &hashlock_1);
+    let root = merkle_node(&e, &leaf_0, &leaf_1);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker_a, &100);
+    _rebate_token.mint(&funder, &100);
+
+    factory.fund_rebate_pool(&funder, &rebate_token.address, &100);
+    factory.set_rebate(&Rebate {
+        token: rebate_token.address.clone(),
+        amount: 15,
+        prompt_window: 100,
+    });
+
+    let immutables = EscrowImmutables {
+        safety_deposit: SafetyDeposit::Flat(10),
+        ..base_immutables(&e, root.clone(), &maker, &token.address, &safety_token.address)
+    };
+    let escrow_a = EscrowClient::new(
+        &e,
+        &factory.create_escrow_for_segment(&immutables, &vec![&e, leaf_1], &0, &hashlock_0, &300, &taker_a),
+    );
+    factory.bind(&hashlock_0, 1);
+    let escrow_b = EscrowClient::new(&e, &escrow_a.split_escrow(&100, &taker_b, &1));
+
+    // The split part fills no segment of its own and earns no rebate
+    assert_eq!(escrow_a.get_resolves().segment_index, Some(0));
+    assert!(escrow_a.get_resolves().rebate);
+    assert_eq!(escrow_b.get_resolves().segment_index, None);
+    assert!(!escrow_b.get_resolves().rebate);
+
+    // Both withdraw promptly, the split part reporting no fill
+    jump_time(&e, 1001);
+    escrow_b.withdraw(&secret_0, &taker_b);
+    assert!(!e.events().all().iter().any(|(_, topics, _)| {
+        topics == (Symbol::new(&e, "htlc"), Symbol::new(&e, "withdraw_fill"), root.clone()).into_val(&e)
+    }));
+    escrow_a.withdraw(&secret_0, &taker_a);
+    let (contract, _, data) = e.events().all().last().unwrap();
+    assert_eq!(contract, escrow_a.address);
+    assert_eq!(<(u32, i128, i128)>::try_from_val(&e, &data).unwrap(), (0, 200, 200));
+
+    // The segment is filled and its secret revealed once, the rebate paid once
+    assert_eq!(factory.revealed_secrets(&root), vec![&e, secret_0]);
+    assert_eq!(rebate_token.balance(&taker_a), 15);
+    assert_eq!(rebate_token.balance(&taker_b), 0);
+    assert_eq!(factory.rebate_pool(&rebate_token.address), 85);
+}
+
+#[test]
+fn test_split_escrow_require_eoa() {
+    let Setup { e, factory, token_sac, token, safety_token_sac, safety_token, maker, taker, .. } = setup();
+
+    let account = Address::from_string(&String::from_str(
+        &e,
+        "GABAEAQCAIBAEAQCAIBAEAQCAIBAEAQCAIBAEAQCAIBAEAQCAIBAEJXA",
+    ));
+    let contract_party = Address::generate(&e);
+
+    // Mint tokens
+    token_sac.mint(&maker, &1000);
+    safety_token_sac.mint(&taker, &100);
+
+    let immutables = EscrowImmutables {
+        amount: AmountCalc::Flat(1000),
+        ..base_immutables(&e, e.crypto().sha256(&generate_secret(&e)).to_bytes(), &maker, &token.address, &safety_token.address)
+    };
+    let escrow = EscrowClient::new(&e, &factory.create_escrow(&immutables, &0, &taker));
+
+    factory.set_require_eoa(&true);
+
+    // The new taker is validated like one creating the escrow
+    factory.bind(&immutables.hashlock, 1);
+    let error = escrow.try_split_escrow(&400, &contract_party, &1);
+    assert_eq!(error.err(), Some(Ok(EscrowError::ContractParty.into())));
+
+    let split = EscrowClient::new(&e, &escrow.split_escrow(&400, &account, &1));
+    assert_eq!(split.get_resolves().taker, account);
+}
@@ -0,0 +1,935 @@
+// lib.rs
+#![no_std]
+use soroban_sdk::{
+    contractclient, contracterror, contracttype, panic_with_error, vec, xdr::ToXdr, Address, Bytes,
+    BytesN, Env, Map, Symbol, Vec,
+};
+
+// Logic version of the factory and the escrows it deploys, bumped as behavior changes
+pub const VERSION: u32 = 2;
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub struct EscrowImmutables {
+    pub hashlock: BytesN<32>, // Hash of the secret
+    // Hash of a second secret also required to withdraw
+    pub second_hashlock: Option<BytesN<32>>,
+    pub hash_algo: HashAlgo, // Algorithm secrets are hashed with
+    pub direction: EscrowDirection,
+    pub maker: Address,
+    // Maker's refund address on the other chain, only relayed on cancel
+    pub refund_address_hint: Bytes,
+    // Where the maker wants the counterparty leg delivered, relayed in the created event
+    pub maker_receiver: Option<Address>,
+    // Resolver who gains the taker's withdrawal rights once backup_withdrawal has passed
+    pub backup_taker: Option<Address>,
+    pub backup_withdrawal: u64,
+    pub token: Address,
+    pub amount: AmountCalc,
+    pub safety_deposit_token: Address,
+    pub safety_deposit: SafetyDeposit,
+    // Additional (token, amount) safety deposits
+    pub extra_safety_deposits: Vec<(Address, i128)>,
+    pub timelocks: TimeLocks, // Timelocks for withdrawal and cancellation
+}
+
+impl EscrowImmutables {
+    // All safety deposits as (token, amount), the primary one first
+    pub fn safety_deposits(&self, env: &Env, principal: i128) -> Vec<(Address, i128)> {
+        let mut deposits = vec![
+            env,
+            (
+                self.safety_deposit_token.clone(),
+                self.safety_deposit.amount(env, principal),
+            ),
+        ];
+        deposits.append(&self.extra_safety_deposits);
+        deposits
+    }
+
+    // Validate the fields that make sense whatever the factory, as checked at creation
+    pub fn validate(&self, env: &Env) {
+        // Validate the amount calculation
+        if !self.amount.is_valid() {
+            panic_with_error!(env, EscrowError::InvalidAmount);
+        }
+
+        // Validate the hashlocks are set, all zeros is a client bug whatever the algorithm
+        let zero = BytesN::from_array(env, &[0; 32]);
+        if self.hashlock == zero || self.second_hashlock == Some(zero) {
+            panic_with_error!(env, EscrowError::ZeroHashlock);
+        }
+
+        // Validate a deposit share is at most the whole principal
+        if let SafetyDeposit::Percent(bps) = self.safety_deposit {
+            if bps as i128 > BPS_DENOMINATOR {
+                panic_with_error!(env, EscrowError::InvalidAmount);
+            }
+        }
+
+        // Validate the timelocks aren't all zero, which opens every window at once
+        if self.timelocks
+            == (TimeLocks {
+                withdrawal: 0,
+                public_withdrawal: 0,
+                cancellation: 0,
+                public_cancellation: 0,
+            })
+        {
+            panic_with_error!(env, EscrowError::InvalidImmutables);
+        }
+
+        // Validate public withdrawal opens before cancellation, so the public
+        // withdrawal window neither is empty nor overlaps cancellation
+        if !self.timelocks.has_public_withdrawal() {
+            panic_with_error!(env, EscrowError::WithdrawalOverlapsCancellation);
+        }
+
+        // Validate the backup taker's window opens between the taker's and the public one
+        if self.backup_taker.is_some()
+            && (self.backup_withdrawal <= self.timelocks.withdrawal
+                || self.backup_withdrawal >= self.timelocks.public_withdrawal)
+        {
+            panic_with_error!(env, EscrowError::InvalidBackupWithdrawal);
+        }
+    }
+
+    // Canonical big-endian encoding, addresses as length-prefixed strkeys,
+    // so every chain hashes identical bytes for the same order
+    pub fn encode(&self, env: &Env) -> Bytes {
+        let mut out = Bytes::new(env);
+        out.extend_from_array(&self.hashlock.to_array());
+        match &self.second_hashlock {
+            Some(second_hashlock) => {
+                out.push_back(1);
+                out.extend_from_array(&second_hashlock.to_array());
+            }
+            None => out.push_back(0),
+        }
+        out.push_back(match self.hash_algo {
+            HashAlgo::Sha256 => 0,
+            HashAlgo::Keccak256 => 1,
+        });
+        out.push_back(match self.direction {
+            EscrowDirection::Maker2Taker => 0,
+            EscrowDirection::Taker2Maker => 1,
+        });
+        encode_address(&mut out, &self.maker);
+        out.extend_from_array(&self.refund_address_hint.len().to_be_bytes());
+        out.append(&self.refund_address_hint);
+        match &self.maker_receiver {
+            Some(maker_receiver) => {
+                out.push_back(1);
+                encode_address(&mut out, maker_receiver);
+            }
+            None => out.push_back(0),
+        }
+        match &self.backup_taker {
+            Some(backup_taker) => {
+                out.push_back(1);
+                encode_address(&mut out, backup_taker);
+            }
+            None => out.push_back(0),
+        }
+        out.extend_from_array(&self.backup_withdrawal.to_be_bytes());
+        encode_address(&mut out, &self.token);
+        encode_amount(&mut out, &self.amount);
+        encode_address(&mut out, &self.safety_deposit_token);
+        match &self.safety_deposit {
+            SafetyDeposit::Flat(amount) => {
+                out.push_back(0);
+                out.extend_from_array(&amount.to_be_bytes());
+            }
+            SafetyDeposit::Percent(bps) => {
+                out.push_back(1);
+                out.extend_from_array(&bps.to_be_bytes());
+            }
+        }
+        out.extend_from_array(&self.extra_safety_deposits.len().to_be_bytes());
+        for (deposit_token, deposit_amount) in self.extra_safety_deposits.iter() {
+            encode_address(&mut out, &deposit_token);
+            out.extend_from_array(&deposit_amount.to_be_bytes());
+        }
+        encode_timelocks(&mut out, &self.timelocks);
+        out
+    }
+
+    // Canonical encoding of the order-defining fields only, matching the
+    // off-chain order hash: hashlock, maker, token, amount spec and timelocks
+    pub fn encode_order(&self, env: &Env) -> Bytes {
+        let mut out = Bytes::new(env);
+        out.extend_from_array(&self.hashlock.to_array());
+        encode_address(&mut out, &self.maker);
+        encode_address(&mut out, &self.token);
+        encode_amount(&mut out, &self.amount);
+        encode_timelocks(&mut out, &self.timelocks);
+        out
+    }
+
+    // Decode immutables from their canonical encoding
+    pub fn decode(env: &Env, bytes: &Bytes) -> Self {
+        let mut pos = 0;
+        let hashlock = BytesN::from_array(env, &take::<32>(env, bytes, &mut pos));
+        let second_hashlock = match take::<1>(env, bytes, &mut pos) {
+            [0] => None,
+            [1] => Some(BytesN::from_array(env, &take::<32>(env, bytes, &mut pos))),
+            _ => panic_with_error!(env, EscrowError::InvalidEncoding),
+        };
+        let hash_algo = match take::<1>(env, bytes, &mut pos) {
+            [0] => HashAlgo::Sha256,
+            [1] => HashAlgo::Keccak256,
+            _ => panic_with_error!(env, EscrowError::InvalidEncoding),
+        };
+        let direction = match take::<1>(env, bytes, &mut pos) {
+            [0] => EscrowDirection::Maker2Taker,
+            [1] => EscrowDirection::Taker2Maker,
+            _ => panic_with_error!(env, EscrowError::InvalidEncoding),
+        };
+        let maker = decode_address(env, bytes, &mut pos);
+        let hint_len = u32::from_be_bytes(take(env, bytes, &mut pos));
+        let hint_end = pos
+            .checked_add(hint_len)
+            .filter(|end| *end <= bytes.len())
+            .unwrap_or_else(|| panic_with_error!(env, EscrowError::InvalidEncoding));
+        let refund_address_hint = bytes.slice(pos..hint_end);
+        pos = hint_end;
+        let maker_receiver = match take::<1>(env, bytes, &mut pos) {
+            [0] => None,
+            [1] => Some(decode_address(env, bytes, &mut pos)),
+            _ => panic_with_error!(env, EscrowError::InvalidEncoding),
+        };
+        let backup_taker = match take::<1>(env, bytes, &mut pos) {
+            [0] => None,
+            [1] => Some(decode_address(env, bytes, &mut pos)),
+            _ => panic_with_error!(env, EscrowError::InvalidEncoding),
+        };
+        let backup_withdrawal = u64::from_be_bytes(take(env, bytes, &mut pos));
+        let token = decode_address(env, bytes, &mut pos);
+        let amount = match take::<1>(env, bytes, &mut pos) {
+            [0] => AmountCalc::Flat(i128::from_be_bytes(take(env, bytes, &mut pos))),
+            [1] => AmountCalc::Linear(DutchAuction {
+                start_time: u64::from_be_bytes(take(env, bytes, &mut pos)),
+                stop_time: u64::from_be_bytes(take(env, bytes, &mut pos)),
+                start_amount: i128::from_be_bytes(take(env, bytes, &mut pos)),
+                stop_amount: i128::from_be_bytes(take(env, bytes, &mut pos)),
+            }),
+            _ => panic_with_error!(env, EscrowError::InvalidEncoding),
+        };
+        let safety_deposit_token = decode_address(env, bytes, &mut pos);
+        let safety_deposit = match take::<1>(env, bytes, &mut pos) {
+            [0] => SafetyDeposit::Flat(i128::from_be_bytes(take(env, bytes, &mut pos))),
+            [1] => SafetyDeposit::Percent(u32::from_be_bytes(take(env, bytes, &mut pos))),
+            _ => panic_with_error!(env, EscrowError::InvalidEncoding),
+        };
+        let mut extra_safety_deposits = Vec::new(env);
+        for _ in 0..u32::from_be_bytes(take(env, bytes, &mut pos)) {
+            let deposit_token = decode_address(env, bytes, &mut pos);
+            let deposit_amount = i128::from_be_bytes(take(env, bytes, &mut pos));
+            extra_safety_deposits.push_back((deposit_token, deposit_amount));
+        }
+        let timelocks = TimeLocks {
+            withdrawal: u64::from_be_bytes(take(env, bytes, &mut pos)),
+            public_withdrawal: u64::from_be_bytes(take(env, bytes, &mut pos)),
+            cancellation: u64::from_be_bytes(take(env, bytes, &mut pos)),
+            public_cancellation: u64::from_be_bytes(take(env, bytes, &mut pos)),
+        };
+
+        // Validate nothing trails the encoding
+        if pos != bytes.len() {
+            panic_with_error!(env, EscrowError::InvalidEncoding);
+        }
+
+        EscrowImmutables {
+            hashlock,
+            second_hashlock,
+            hash_algo,
+            direction,
+            maker,
+            refund_address_hint,
+            maker_receiver,
+            backup_taker,
+            backup_withdrawal,
+            token,
+            amount,
+            safety_deposit_token,
+            safety_deposit,
+            extra_safety_deposits,
+            timelocks,
+        }
+    }
+}
+
+// Builds immutables with defaults for everything but the order's core: a flat amount,
+// no safety deposit and the standard timelocks, validated as creation would
+pub struct EscrowImmutablesBuilder {
+    env: Env,
+    immutables: EscrowImmutables,
+}
+
+impl EscrowImmutablesBuilder {
+    pub fn new(
+        env: &Env,
+        hashlock: BytesN<32>,
+        maker: Address,
+        token: Address,
+        amount: i128,
+    ) -> Self {
+        EscrowImmutablesBuilder {
+            env: env.clone(),
+            immutables: EscrowImmutables {
+                hashlock,
+                second_hashlock: None,
+                hash_algo: HashAlgo::Sha256,
+                direction: EscrowDirection::Maker2Taker,
+                maker,
+                refund_address_hint: Bytes::new(env),
+                maker_receiver: None,
+                backup_taker: None,
+                backup_withdrawal: 0,
+                token: token.clone(),
+                amount: AmountCalc::Flat(amount),
+                safety_deposit_token: token,
+                safety_deposit: SafetyDeposit::Flat(0),
+                extra_safety_deposits: vec![env],
+                timelocks: DEFAULT_TIMELOCKS,
+            },
+        }
+    }
+
+    pub fn second_hashlock(mut self, second_hashlock: BytesN<32>) -> Self {
+        self.immutables.second_hashlock = Some(second_hashlock);
+        self
+    }
+
+    pub fn hash_algo(mut self, hash_algo: HashAlgo) -> Self {
+        self.immutables.hash_algo = hash_algo;
+        self
+    }
+
+    pub fn direction(mut self, direction: EscrowDirection) -> Self {
+        self.immutables.direction = direction;
+        self
+    }
+
+    pub fn refund_address_hint(mut self, refund_address_hint: Bytes) -> Self {
+        self.immutables.refund_address_hint = refund_address_hint;
+        self
+    }
+
+    pub fn maker_receiver(mut self, maker_receiver: Address) -> Self {
+        self.immutables.maker_receiver = Some(maker_receiver);
+        self
+    }
+
+    pub fn backup_taker(mut self, backup_taker: Address, backup_withdrawal: u64) -> Self {
+        self.immutables.backup_taker = Some(backup_taker);
+        self.immutables.backup_withdrawal = backup_withdrawal;
+        self
+    }
+
+    pub fn amount(mut self, amount: AmountCalc) -> Self {
+        self.immutables.amount = amount;
+        self
+    }
+
+    pub fn safety_deposit(mut self, token: Address, amount: i128) -> Self {
+        self.immutables.safety_deposit_token = token;
+        self.immutables.safety_deposit = SafetyDeposit::Flat(amount);
+        self
+    }
+
+    pub fn extra_safety_deposit(mut self, token: Address, amount: i128) -> Self {
+        self.immutables
+            .extra_safety_deposits
+            .push_back((token, amount));
+        self
+    }
+
+    pub fn timelocks(mut self, timelocks: TimeLocks) -> Self {
+        self.immutables.timelocks = timelocks;
+        self
+    }
+
+    pub fn build(self) -> EscrowImmutables {
+        self.immutables.validate(&self.env);
+        self.immutables
+    }
+}
+
+// Longest strkey an address encodes to
+const MAX_STRKEY_LEN: usize = 69;
+const STRKEY_LEN: usize = 56;
+const STRKEY_ACCOUNT: u8 = 6 << 3;
+const STRKEY_CONTRACT: u8 = 2 << 3;
+
+// Whether the address is a contract rather than an account, judged by its strkey
+pub fn is_contract_address(address: &Address) -> bool {
+    let strkey = address.to_string();
+    let mut buf = [0u8; MAX_STRKEY_LEN];
+    strkey.copy_into_slice(&mut buf[..strkey.len() as usize]);
+    buf[0] == b'C'
+}
+
+fn encode_address(out: &mut Bytes, address: &Address) {
+    let strkey = address.to_string();
+    let len = strkey.len() as usize;
+    let mut buf = [0u8; MAX_STRKEY_LEN];
+    strkey.copy_into_slice(&mut buf[..len]);
+    out.push_back(len as u8);
+    out.extend_from_slice(&buf[..len]);
+}
+
+fn encode_amount(out: &mut Bytes, amount: &AmountCalc) {
+    match amount {
+        AmountCalc::Flat(amount) => {
+            out.push_back(0);
+            out.extend_from_array(&amount.to_be_bytes());
+        }
+        AmountCalc::Linear(da) => {
+            out.push_back(1);
+            out.extend_from_array(&da.start_time.to_be_bytes());
+            out.extend_from_array(&da.stop_time.to_be_bytes());
+            out.extend_from_array(&da.start_amount.to_be_bytes());
+            out.extend_from_array(&da.stop_amount.to_be_bytes());
+        }
+    }
+}
+
+fn encode_timelocks(out: &mut Bytes, timelocks: &TimeLocks) {
+    out.extend_from_array(&timelocks.withdrawal.to_be_bytes());
+    out.extend_from_array(&timelocks.public_withdrawal.to_be_bytes());
+    out.extend_from_array(&timelocks.cancellation.to_be_bytes());
+    out.extend_from_array(&timelocks.public_cancellation.to_be_bytes());
+}
+
+fn decode_address(env: &Env, bytes: &Bytes, pos: &mut u32) -> Address {
+    let [len] = take::<1>(env, bytes, pos);
+    let end = *pos + len as u32;
+    if len as usize > MAX_STRKEY_LEN || end > bytes.len() {
+        panic_with_error!(env, EscrowError::InvalidEncoding);
+    }
+
+    // Validate the strkey here, as the host traps on a malformed one
+    let mut buf = [0u8; MAX_STRKEY_LEN];
+    bytes
+        .slice(*pos..end)
+        .copy_into_slice(&mut buf[..len as usize]);
+    if !is_valid_strkey(&buf[..len as usize]) {
+        panic_with_error!(env, EscrowError::InvalidEncoding);
+    }
+    let address = Address::from_string_bytes(&bytes.slice(*pos..end));
+    *pos = end;
+    address
+}
+
+// Whether the bytes are the strkey of an account or a contract, the only ones an
+// address is made from: base32 of the version byte, 32 byte key and CRC16 checksum
+fn is_valid_strkey(strkey: &[u8]) -> bool {
+    if strkey.len() != STRKEY_LEN {
+        return false;
+    }
+
+    let mut decoded = [0u8; 35];
+    let (mut acc, mut bits, mut i) = (0u32, 0u32, 0usize);
+    for c in strkey {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return false,
+        };
+        acc = ((acc << 5) | value as u32) & 0x1fff;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded[i] = (acc >> bits) as u8;
+            i += 1;
+        }
+    }
+
+    let checksum = u16::from_le_bytes([decoded[33], decoded[34]]);
+    matches!(decoded[0], STRKEY_ACCOUNT | STRKEY_CONTRACT)
+        && crc16_xmodem(&decoded[..33]) == checksum
+}
+
+fn crc16_xmodem(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn take<const N: usize>(env: &Env, bytes: &Bytes, pos: &mut u32) -> [u8; N] {
+    let end = *pos + N as u32;
+    if end > bytes.len() {
+        panic_with_error!(env, EscrowError::InvalidEncoding);
+    }
+    let mut buf = [0u8; N];
+    bytes.slice(*pos..end).copy_into_slice(&mut buf);
+    *pos = end;
+    buf
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub enum EscrowDirection {
+    Maker2Taker,
+    Taker2Maker,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub enum HashAlgo {
+    Sha256,
+    Keccak256,
+}
+
+impl HashAlgo {
+    pub fn hash(&self, env: &Env, secret: &Bytes) -> BytesN<32> {
+        match self {
+            HashAlgo::Sha256 => env.crypto().sha256(secret).to_bytes(),
+            HashAlgo::Keccak256 => env.crypto().keccak256(secret).to_bytes(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub enum AmountCalc {
+    Flat(i128),
+    Linear(DutchAuction),
+}
+
+impl AmountCalc {
+    pub fn calc(&self, timestamp: u64) -> i128 {
+        self.checked_calc(timestamp).unwrap()
+    }
+
+    // Amount at the timestamp, None if interpolating it overflows
+    pub fn checked_calc(&self, timestamp: u64) -> Option<i128> {
+        match self {
+            AmountCalc::Flat(amount) => Some(*amount),
+            AmountCalc::Linear(da) => {
+                let ts = timestamp.clamp(da.start_time, da.stop_time);
+                let a = da.start_amount.checked_mul((da.stop_time - ts) as i128)?;
+                let b = da.stop_amount.checked_mul((ts - da.start_time) as i128)?;
+                a.checked_add(b)?
+                    .checked_div((da.stop_time - da.start_time) as i128)
+            }
+        }
+    }
+
+    // Time-weighted average amount over the auction window, for a linear auction
+    // the area under the line over its duration, so the mean of both ends. The ends
+    // are halved before adding, so the mean can't overflow where their sum would
+    pub fn twap(&self) -> i128 {
+        match self {
+            AmountCalc::Flat(amount) => *amount,
+            AmountCalc::Linear(da) => {
+                da.start_amount / 2
+                    + da.stop_amount / 2
+                    + (da.start_amount % 2 + da.stop_amount % 2) / 2
+            }
+        }
+    }
+
+    // Whether the calculation can't yield negative amounts, auctions running
+    // forward in time with a non-increasing amount
+    pub fn is_valid(&self) -> bool {
+        match self {
+            AmountCalc::Flat(_) => true,
+            AmountCalc::Linear(da) => {
+                da.start_time < da.stop_time
+                    && da.stop_amount >= 0
+                    && da.start_amount >= da.stop_amount
+            }
+        }
+    }
+}
+
+// Primary safety deposit, either an amount or a share of the principal in basis points
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub enum SafetyDeposit {
+    Flat(i128),
+    Percent(u32),
+}
+
+impl SafetyDeposit {
+    // Amount of the deposit for the principal, shares rounding down
+    pub fn amount(&self, env: &Env, principal: i128) -> i128 {
+        match self {
+            SafetyDeposit::Flat(amount) => *amount,
+            SafetyDeposit::Percent(bps) => {
+                principal
+                    .checked_mul(*bps as i128)
+                    .unwrap_or_else(|| panic_with_error!(env, EscrowError::InvalidAmount))
+                    / BPS_DENOMINATOR
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub struct DutchAuction {
+    pub start_time: u64,
+    pub stop_time: u64,
+    pub start_amount: i128,
+    pub stop_amount: i128,
+}
+
+// Clock Dutch auction times are measured against
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub enum ClockSource {
+    Timestamp,
+    Sequence,
+}
+
+impl ClockSource {
+    pub fn now(&self, env: &Env) -> u64 {
+        match self {
+            ClockSource::Timestamp => env.ledger().timestamp(),
+            ClockSource::Sequence => env.ledger().sequence() as u64,
+        }
+    }
+}
+
+// Timelocks of escrows built without explicit ones: an hour of finality, then an hour
+// each of private withdrawal, public withdrawal and private cancellation
+pub const DEFAULT_TIMELOCKS: TimeLocks = TimeLocks {
+    withdrawal: 3600,
+    public_withdrawal: 7200,
+    cancellation: 10_800,
+    public_cancellation: 14_400,
+};
+
+// Number of most recent escrows indexed per taker
+pub const MAX_ESCROWS_PER_TAKER: u32 = 50;
+
+// Escrow storage lifetime in ledgers, extended once it drops below the threshold
+pub const ESCROW_TTL_EXTEND_TO: u32 = 30 * 17_280;
+pub const ESCROW_TTL_THRESHOLD: u32 = ESCROW_TTL_EXTEND_TO - 17_280;
+
+// Number of parts an order can be split into unless configured otherwise
+pub const DEFAULT_MAX_PARTS: u32 = 100;
+
+// Number of escrows that can be queried in one batch
+pub const MAX_BATCH_QUERY: u32 = 25;
+
+// Basis points denominator of fee rates
+pub const BPS_DENOMINATOR: i128 = 10_000;
+
+// Longest secret a withdrawal accepts, in bytes
+pub const MAX_SECRET_LEN: u32 = 64;
+
+// Seconds past public cancellation before the guardian may force-cancel an escrow
+pub const GUARDIAN_GRACE: u64 = 30 * 86_400;
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub enum FeeRounding {
+    RoundDown, // Truncate, in favor of the user
+    RoundUp,   // Round fractions up, in favor of the protocol
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub struct ProtocolFee {
+    pub bps: u32,
+    pub rounding: FeeRounding,
+}
+
+impl ProtocolFee {
+    pub fn calc(&self, env: &Env, amount: i128) -> i128 {
+        let fee = amount
+            .checked_mul(self.bps as i128)
+            .unwrap_or_else(|| panic_with_error!(env, EscrowError::InvalidAmount));
+        match self.rounding {
+            FeeRounding::RoundDown => fee / BPS_DENOMINATOR,
+            FeeRounding::RoundUp => {
+                fee.checked_add(BPS_DENOMINATOR - 1)
+                    .unwrap_or_else(|| panic_with_error!(env, EscrowError::InvalidAmount))
+                    / BPS_DENOMINATOR
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub struct TimeLocks {
+    pub withdrawal: u64,
+    pub public_withdrawal: u64,
+    pub cancellation: u64,
+    pub public_cancellation: u64,
+}
+
+impl TimeLocks {
+    // Whether anyone may withdraw for some time before cancellation opens
+    pub fn has_public_withdrawal(&self) -> bool {
+        self.public_withdrawal < self.cancellation
+    }
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct EscrowResolves {
+    pub factory: Address,
+    pub taker: Address,
+    pub amount: i128,
+    pub priced_amount: i128, // Amount the order was priced at, of which amount is the share once split
+    pub safety_deposit: i128, // Primary safety deposit, fixed at the principal pulled at creation
+    pub hashlock: BytesN<32>, // Hashlock unlocking the escrow, as amended by both parties
+    pub timestamp: u64,
+    pub withdrawal: u64, // Delay opening the taker's withdrawal, as opened early by both parties
+    pub dispute_window: u64,
+    pub order_hashlock: BytesN<32>, // Hashlock of the order the escrow fills
+    pub segment_index: Option<u32>, // Index of the segment, for segment escrows
+    pub rebate: bool, // Whether the taker withdrawing promptly earns the rebate, split parts not
+    pub ttl_bump: u32,
+    pub keeper_reward: i128, // Share of the safety deposit paid to keepers cancelling
+    pub topic_prefix: Symbol, // Prefix of the factory's event topics
+    pub amount_valid_until: Option<u64>, // Past it the taker may reprice at the current auction level
+    pub reward_ramp: u64, // Delay over which the public caller's reward grows to the full deposit
+    pub maker_auth_cancel: bool, // Whether private cancellation also needs the maker's auth
+    pub cancel_oracle: Option<BytesN<32>>, // Key attesting source cancellations, if any
+    pub no_safety_deposit: bool, // Whether the safety deposits are ignored altogether
+    pub protocol_fee: ProtocolFee, // Fee taken from the principal on withdrawal
+    pub secret_registry: Option<Address>, // Registry the secrets are pushed to on withdrawal
+    pub clock_source: ClockSource, // Clock the auction is evaluated on
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub enum EscrowState {
+    Active,
+    Pending, // Withdrawn, funds held until the dispute window ends
+    Frozen,  // Withdrawn, funds frozen by the admin pending a dispute
+    Withdrawn,
+    Cancelled,
+    Settling, // Funds being paid out by a withdrawal or cancel, only seen on re-entry
+}
+
+// Latest timelock window opened since creation
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub enum EscrowPhase {
+    Locked, // No window open yet
+    Withdrawal,
+    PublicWithdrawal,
+    Cancellation,
+    PublicCancellation,
+}
+
+// Registry escrows push revealed secrets to, keyed by hashlock
+#[contractclient(name = "SecretRegistryClient")]
+pub trait SecretRegistry {
+    fn push_secret(env: Env, hashlock: BytesN<32>, secret: Bytes);
+}
+
+// Bonus paid from the factory pool to takers withdrawing promptly
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub struct Rebate {
+    pub token: Address,
+    pub amount: i128,
+    pub prompt_window: u64, // Seconds after the withdrawal opens the rebate is paid within
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub struct FactoryConfig {
+    pub admin: Address,
+    pub dispute_window: u64,
+    pub max_parts: u32,
+    pub min_amount: i128,
+    pub clock_source: ClockSource,
+    pub public_cooldown: u64,
+    pub ttl_bump: u32,
+    pub keeper_reward: i128,
+    pub topic_prefix: Symbol,
+    pub escrow_wasm_hash: BytesN<32>,
+    pub amount_validity: u64,
+    pub reward_ramp: u64,
+    pub maker_auth_cancel: bool,
+    pub max_future: u64,
+    pub cancel_oracle: Option<BytesN<32>>,
+    pub no_safety_deposit: bool,
+    pub protocol_fee: ProtocolFee,
+    pub guardian: Option<Address>,
+    pub require_eoa: bool,
+    pub secret_registry: Option<Address>,
+    pub deposit_tokens: Vec<Address>,
+    pub min_transfers: Map<Address, i128>,
+    pub pending_admin: Option<Address>,
+    pub rebate_token: Option<Address>, // Rebate, flattened as contract types cannot nest an Option of a struct
+    pub rebate_amount: i128,
+    pub rebate_prompt_window: u64,
+    pub rebate_pool: i128, // Pool of the current rebate token
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub struct CallerEconomics {
+    pub payee: Address, // Destination of the principal on withdrawal
+    pub safety_deposits: Vec<(Address, i128)>, // Paid to the caller on withdrawal now
+    pub can_withdraw: bool, // Whether the caller may withdraw now given the secret
+}
+
+// What create_escrow would do with immutables now, for resolvers deciding to fill
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub struct Quote {
+    pub amount: i128,         // Principal pulled from the funder
+    pub safety_deposit: i128, // Primary safety deposit pulled from the taker
+    pub timelocks: TimeLocks, // Timestamps each window opens at
+    pub address: Address,     // Address the escrow deploys at
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub struct EscrowOverview {
+    pub state: EscrowState,
+    pub phase: EscrowPhase,
+    pub amount: i128, // Principal the escrow settles
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[contracttype]
+pub enum CallerRole {
+    Taker,
+    Maker,
+    Public,
+}
+
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum EscrowError {
+    AlreadyTaken = 1,
+    NotActive = 2,
+    Unauthorized = 3,
+    TooEarly = 4,
+    InvalidSecret = 5,
+    SecretRevealed = 6,
+    InvalidCreationTime = 7,
+    TooLate = 8,
+    InvalidMerkleProof = 9,
+    InvalidPartialFill = 10,
+    NotPending = 11,
+    NotFrozen = 12,
+    BatchTooLarge = 13,
+    TooManyParts = 14,
+    BelowMinTransfer = 15,
+    InvalidEncoding = 16,
+    AmountTooSmall = 17,
+    InvalidAmount = 18,
+    InvalidImmutables = 19,
+    CooldownActive = 20,
+    DuplicateDepositToken = 21,
+    DepositTokenNotAllowed = 22,
+    Underfunded = 23,
+    CounterpartyNotFunded = 24,
+    ZeroHashlock = 25,
+    TimelockTooFar = 26,
+    AmountOverflow = 27,
+    SegmentCancelled = 28,
+    AddressMismatch = 29,
+    WithdrawalOverlapsCancellation = 30,
+    InvalidBackupWithdrawal = 31,
+    ContractParty = 32,
+    SettlementInProgress = 33,
+}
+
+// Hashlock of a secret as escrows verify it, the one path clients and tooling should use
+pub fn derive_hashlock(env: &Env, secret: &Bytes, algo: &HashAlgo) -> BytesN<32> {
+    algo.hash(env, secret)
+}
+
+// Message the cancel oracle signs to attest the source escrow of an order was cancelled,
+// bound to the factory and network so an attestation can't be replayed on another deployment
+pub fn source_cancelled_message(
+    env: &Env,
+    factory: &Address,
+    order_hashlock: &BytesN<32>,
+) -> Bytes {
+    let mut message = Bytes::from_slice(env, b"src_cancelled");
+    message.append(&factory.clone().to_xdr(env));
+    message.append(&Bytes::from(env.ledger().network_id()));
+    message.append(&Bytes::from(order_hashlock.clone()));
+    message
+}
+
+// Merkle leaf of a partial fill segment, committing to the amount it fills
+pub fn merkle_leaf(env: &Env, index: u32, amount: i128, hashlock: &BytesN<32>) -> BytesN<32> {
+    let mut data = Bytes::from_array(env, &index.to_be_bytes());
+    data.extend_from_array(&amount.to_be_bytes());
+    data.append(&Bytes::from(hashlock.clone()));
+    env.crypto().sha256(&data).to_bytes()
+}
+
+// Parent of two Merkle nodes, hashed as a sorted pair
+pub fn merkle_node(env: &Env, a: &BytesN<32>, b: &BytesN<32>) -> BytesN<32> {
+    let (left, right) = if a < b { (a, b) } else { (b, a) };
+    let mut data = Bytes::from(left.clone());
+    data.append(&Bytes::from(right.clone()));
+    env.crypto().sha256(&data).to_bytes()
+}
+
+// Root committed to by a leaf and its Merkle proof
+pub fn merkle_root(env: &Env, leaf: BytesN<32>, proof: &Vec<BytesN<32>>) -> BytesN<32> {
+    proof
+        .iter()
+        .fold(leaf, |node, sibling| merkle_node(env, &node, &sibling))
+}
+
+// Factory functions escrows call back into
+#[contractclient(name = "EscrowFactoryClient")]
+pub trait EscrowFactoryInterface {
+    fn admin(env: Env) -> Address;
+    fn is_revealed(env: Env, hashlock: BytesN<32>) -> bool;
+    fn min_transfer(env: Env, token: Address) -> i128;
+    fn record_fill(
+        env: Env,
+        escrow: Address,
+        order_hashlock: BytesN<32>,
+        amount: i128,
+        secret: Bytes,
+    ) -> i128;
+    fn record_public_withdrawal(env: Env, escrow: Address, caller: Address);
+    fn record_settlement(env: Env, escrow: Address, token: Address, amount: i128, withdrawn: bool);
+    fn record_fee(env: Env, escrow: Address, token: Address, amount: i128);
+    fn pay_rebate(env: Env, escrow: Address, taker: Address, withdrawal_start: u64) -> i128;
+    fn deploy_split(
+        env: Env,
+        escrow: Address,
+        resolves: EscrowResolves,
+        immutables: EscrowImmutables,
+        amount: i128,
+        nonce: u64,
+        taker: Address,
+    ) -> Address;
+    fn reveal(env: Env, escrow: Address, secret: Bytes, algo: HashAlgo);
+    fn begin_settlement(env: Env, escrow: Address, hashlock: BytesN<32>);
+    fn end_settlement(env: Env, escrow: Address, hashlock: BytesN<32>);
+}
+
+// Escrow functions the factory calls on the escrows it deployed
+#[contractclient(name = "EscrowClient")]
+pub trait EscrowInterface {
+    fn initialize(env: Env, immutables: EscrowImmutables, resolves: EscrowResolves);
+    fn get_immutables(env: Env) -> EscrowImmutables;
+    fn get_resolves(env: Env) -> EscrowResolves;
+    fn get_state(env: Env) -> EscrowState;
+    fn phase(env: Env) -> EscrowPhase;
+    fn force_cancel(env: Env) -> bool;
+}
@@ -0,0 +1,1444 @@
+// lib.rs
+#![no_std]
+use escrow_interface::{
+    source_cancelled_message, CallerEconomics, CallerRole, EscrowDirection, EscrowError,
+    EscrowFactoryClient, EscrowImmutables, EscrowPhase, EscrowResolves, EscrowState, HashAlgo,
+    SafetyDeposit, SecretRegistryClient, TimeLocks, ESCROW_TTL_EXTEND_TO, ESCROW_TTL_THRESHOLD,
+    MAX_SECRET_LEN, VERSION,
+};
+use soroban_sdk::{
+    contract, contractimpl, contractmeta, panic_with_error, token, vec, xdr::ToXdr, Address, Bytes,
+    BytesN, Env, Symbol, Vec,
+};
+
+contractmeta!(
+    key = "Description",
+    val = "Bare-bone cross-chain atomic swap escrow deployed by the escrow factory"
+);
+
+#[contract]
+pub struct Escrow;
+
+#[contractimpl]
+impl Escrow {
+    // Initialize escrow with immutables
+    pub fn initialize(env: Env, immutables: EscrowImmutables, resolves: EscrowResolves) {
+        if env.storage().instance().has(&Symbol::new(&env, "state")) {
+            panic_with_error!(&env, EscrowError::AlreadyTaken);
+        }
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "state"), &EscrowState::Active);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "immutables"), &immutables);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "resolves"), &resolves);
+        env.storage().instance().set(
+            &Symbol::new(&env, "immutables_hash"),
+            &env.crypto().sha256(&immutables.encode(&env)).to_bytes(),
+        );
+        Self::bump_ttl(&env);
+    }
+
+    // Extend the escrow storage lifetime, run after restoring archived entries
+    // with the host so an unsettled escrow stays usable
+    pub fn restore(env: Env) {
+        env.storage()
+            .instance()
+            .extend_ttl(ESCROW_TTL_THRESHOLD, ESCROW_TTL_EXTEND_TO);
+    }
+
+    // Withdraw funds with secret
+    pub fn withdraw(env: Env, secret: Bytes, caller: Address) {
+        Self::settle_withdraw(env.clone(), vec![&env, secret.clone()], caller);
+
+        // Emit event
+        env.events()
+            .publish(Self::topic(&env, "withdraw"), (secret,));
+        Self::report_fill(&env);
+    }
+
+    // Withdraw funds with a 32-byte secret, the type the standalone escrow takes
+    pub fn withdraw_fixed(env: Env, secret: BytesN<32>, caller: Address) {
+        Self::withdraw(env, secret.into(), caller);
+    }
+
+    // Withdraw funds with a secret for each hashlock of the escrow
+    pub fn withdraw_multi(env: Env, secrets: Vec<Bytes>, caller: Address) {
+        Self::settle_withdraw(env.clone(), secrets.clone(), caller);
+
+        // Emit event
+        env.events()
+            .publish(Self::topic(&env, "withdraw"), (secrets,));
+        Self::report_fill(&env);
+    }
+
+    // Cancel escrow and return funds
+    pub fn cancel(env: Env, caller: Address) {
+        let immutables: EscrowImmutables = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "immutables"))
+            .unwrap();
+
+        let resolves: EscrowResolves = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "resolves"))
+            .unwrap();
+
+        let state: EscrowState = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "state"))
+            .unwrap();
+
+        // Validate state
+        if !matches!(state, EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Validate time, a window starting past u64::MAX never opens
+        let start = resolves
+            .timestamp
+            .checked_add(if caller == resolves.taker {
+                immutables.timelocks.cancellation
+            } else {
+                immutables.timelocks.public_cancellation
+            })
+            .unwrap_or_else(|| panic_with_error!(&env, EscrowError::TooEarly));
+        // Withdrawal takes precedence, so even a misconfigured public cancellation
+        // can't start before the withdrawal window has closed
+        let withdrawal_end = resolves
+            .timestamp
+            .saturating_add(immutables.timelocks.cancellation);
+        let timestamp = env.ledger().timestamp();
+        if timestamp < start || timestamp < withdrawal_end {
+            panic_with_error!(&env, EscrowError::TooEarly);
+        }
+
+        // Validate the secrets aren't all revealed
+        if Self::secrets_revealed(&env, &immutables, &resolves) {
+            panic_with_error!(&env, EscrowError::SecretRevealed);
+        }
+
+        // Require caller's auth
+        caller.require_auth();
+
+        // Require maker's auth too within the private window, if configured
+        let public_start = resolves
+            .timestamp
+            .saturating_add(immutables.timelocks.public_cancellation);
+        if resolves.maker_auth_cancel && timestamp < public_start && caller != immutables.maker {
+            immutables.maker.require_auth();
+        }
+
+        Self::refund(&env, &immutables, &resolves, &caller, None);
+    }
+
+    // Refund an escrow once public cancellation is open, paying the keeper the fixed
+    // keeper reward out of the safety deposit, the taker getting the rest of the
+    // deposits back
+    pub fn keeper_cancel(env: Env, keeper: Address) {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Validate time, after both public cancellation and withdrawal's end
+        let start = resolves
+            .timestamp
+            .checked_add(immutables.timelocks.public_cancellation)
+            .unwrap_or_else(|| panic_with_error!(&env, EscrowError::TooEarly))
+            .max(
+                resolves
+                    .timestamp
+                    .saturating_add(immutables.timelocks.cancellation),
+            );
+        if env.ledger().timestamp() < start {
+            panic_with_error!(&env, EscrowError::TooEarly);
+        }
+
+        // Validate the secrets aren't all revealed
+        if Self::secrets_revealed(&env, &immutables, &resolves) {
+            panic_with_error!(&env, EscrowError::SecretRevealed);
+        }
+
+        // Require keeper's auth
+        keeper.require_auth();
+
+        Self::refund(
+            &env,
+            &immutables,
+            &resolves,
+            &keeper,
+            Some(resolves.keeper_reward),
+        );
+    }
+
+    // Refund an abandoned escrow once public cancellation is open, doing nothing
+    // if it has already been settled
+    pub fn expire(env: Env, caller: Address) {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        if !matches!(Self::get_state(env.clone()), EscrowState::Active) {
+            return;
+        }
+
+        // Validate time, a window starting past u64::MAX never opens
+        let start = resolves
+            .timestamp
+            .checked_add(immutables.timelocks.public_cancellation)
+            .unwrap_or_else(|| panic_with_error!(&env, EscrowError::TooEarly));
+        if env.ledger().timestamp() < start {
+            panic_with_error!(&env, EscrowError::TooEarly);
+        }
+
+        Self::cancel(env, caller);
+    }
+
+    // Cancel right away once the oracle configured at creation attests the source
+    // escrow of the order was cancelled, rather than waiting for the cancellation window
+    pub fn cancel_on_source_cancelled(env: Env, signature: BytesN<64>) {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Validate the secrets aren't all revealed
+        if Self::secrets_revealed(&env, &immutables, &resolves) {
+            panic_with_error!(&env, EscrowError::SecretRevealed);
+        }
+
+        // Require taker's auth
+        resolves.taker.require_auth();
+
+        // Validate the attestation, an invalid signature failing the call
+        let oracle = resolves
+            .cancel_oracle
+            .clone()
+            .unwrap_or_else(|| panic_with_error!(&env, EscrowError::Unauthorized));
+        env.crypto().ed25519_verify(
+            &oracle,
+            &source_cancelled_message(&env, &resolves.factory, &resolves.order_hashlock),
+            &signature,
+        );
+
+        Self::refund(&env, &immutables, &resolves, &resolves.taker, None);
+    }
+
+    // Record that the escrow token refuses to move the escrow's funds, such as a frozen
+    // asset balance, so tooling can flag the escrow. Cancel stays retryable and clears
+    // the record once it goes through. Returns whether the funds are stuck
+    pub fn mark_stuck(env: Env, caller: Address) -> bool {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Require caller's auth
+        caller.require_auth();
+
+        // Probe the token by moving the funds to the escrow itself, which changes nothing
+        let sender = env.current_contract_address();
+        let stuck = !matches!(
+            token::Client::new(&env, &immutables.token).try_transfer(
+                &sender,
+                &sender,
+                &resolves.amount
+            ),
+            Ok(Ok(()))
+        );
+
+        let stuck_key = Symbol::new(&env, "stuck_since");
+        if !stuck {
+            env.storage().instance().remove(&stuck_key);
+            return false;
+        }
+        if !env.storage().instance().has(&stuck_key) {
+            env.storage()
+                .instance()
+                .set(&stuck_key, &env.ledger().timestamp());
+        }
+        Self::bump_ttl(&env);
+
+        // Emit event
+        env.events().publish(Self::topic(&env, "stuck"), (caller,));
+        true
+    }
+
+    // Cancel the escrow on behalf of its factory's guardian, attempting each refund
+    // transfer on its own so a frozen token only keeps its own funds. The factory
+    // isn't called back, the settlement staying out of its volume stats. Returns
+    // whether the principal was recovered
+    pub fn force_cancel(env: Env) -> bool {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        // Require the factory's auth, which checks the guardian, time and that the secret
+        // is still unknown
+        resolves.factory.require_auth();
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        let payee = match immutables.direction {
+            EscrowDirection::Maker2Taker => &immutables.maker,
+            EscrowDirection::Taker2Maker => &resolves.taker,
+        };
+
+        // Transfer what the tokens let go, principal back and deposits to the taker
+        Self::set_settling(&env);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "deposit_recipient"), &resolves.taker);
+        let sender = env.current_contract_address();
+        let recovered = matches!(
+            token::Client::new(&env, &immutables.token).try_transfer(
+                &sender,
+                payee,
+                &resolves.amount
+            ),
+            Ok(Ok(()))
+        );
+        for (deposit_token, deposit_amount) in Self::deposits(&env, &immutables, &resolves) {
+            if deposit_amount > 0 {
+                let _ = token::Client::new(&env, &deposit_token).try_transfer(
+                    &sender,
+                    &resolves.taker,
+                    &deposit_amount,
+                );
+            }
+        }
+
+        // Update state
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "state"), &EscrowState::Cancelled);
+        if recovered {
+            env.storage()
+                .instance()
+                .remove(&Symbol::new(&env, "stuck_since"));
+        }
+        Self::bump_ttl(&env);
+
+        // Emit event
+        env.events()
+            .publish(Self::topic(&env, "force_cancel"), (recovered,));
+        recovered
+    }
+
+    // Get who received the safety deposits on withdrawal or cancellation, the caller
+    // that settled the escrow, or None while it hasn't settled
+    pub fn deposit_recipient(env: Env) -> Option<Address> {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "deposit_recipient"))
+    }
+
+    // Get when the escrow's funds were first found stuck, if they still are
+    pub fn stuck_since(env: Env) -> Option<u64> {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "stuck_since"))
+    }
+
+    // Release pending funds to the payee once the dispute window is over
+    pub fn finalize(env: Env) {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Pending) {
+            panic_with_error!(&env, EscrowError::NotPending);
+        }
+
+        // Validate time
+        let pending_until: u64 = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "pending_until"))
+            .unwrap();
+        if env.ledger().timestamp() < pending_until {
+            panic_with_error!(&env, EscrowError::TooEarly);
+        }
+
+        let payee = match immutables.direction {
+            EscrowDirection::Maker2Taker => &resolves.taker,
+            EscrowDirection::Taker2Maker => &immutables.maker,
+        };
+
+        // Transfer tokens, less the protocol fee
+        let fee = Self::charge_fee(&env, &immutables, &resolves);
+        token::Client::new(&env, &immutables.token).transfer(
+            &env.current_contract_address(),
+            payee,
+            &(resolves.amount - fee),
+        );
+
+        // Update state
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "state"), &EscrowState::Withdrawn);
+        Self::bump_ttl(&env);
+
+        // Emit event
+        env.events().publish(Self::topic(&env, "finalize"), ());
+    }
+
+    // Freeze pending funds for a dispute, factory admin only
+    pub fn freeze(env: Env) {
+        let resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Pending) {
+            panic_with_error!(&env, EscrowError::NotPending);
+        }
+
+        // Validate time
+        let pending_until: u64 = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "pending_until"))
+            .unwrap();
+        if env.ledger().timestamp() >= pending_until {
+            panic_with_error!(&env, EscrowError::TooLate);
+        }
+
+        // Require admin's auth
+        EscrowFactoryClient::new(&env, &resolves.factory)
+            .admin()
+            .require_auth();
+
+        // Update state
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "state"), &EscrowState::Frozen);
+        Self::bump_ttl(&env);
+
+        // Emit event
+        env.events().publish(Self::topic(&env, "freeze"), ());
+    }
+
+    // Settle a frozen escrow by sending the funds to the recipient chosen by the admin
+    pub fn resolve_dispute(env: Env, recipient: Address) {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Frozen) {
+            panic_with_error!(&env, EscrowError::NotFrozen);
+        }
+
+        // Require admin's auth
+        EscrowFactoryClient::new(&env, &resolves.factory)
+            .admin()
+            .require_auth();
+
+        // Transfer tokens
+        token::Client::new(&env, &immutables.token).transfer(
+            &env.current_contract_address(),
+            &recipient,
+            &resolves.amount,
+        );
+
+        // Update state
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "state"), &EscrowState::Withdrawn);
+        Self::bump_ttl(&env);
+
+        // Emit event
+        env.events()
+            .publish(Self::topic(&env, "resolve_dispute"), (recipient,));
+    }
+
+    // Carve part of the locked amount off into a new escrow for another taker, under
+    // the same hashlock and timelocks, without safety deposits, at the address the
+    // factory predicts for the nonce. Taker only
+    pub fn split_escrow(env: Env, amount_a: i128, taker_b: Address, nonce: u64) -> Address {
+        let immutables = Self::get_immutables(env.clone());
+        let mut resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Validate both escrows keep something
+        if amount_a <= 0 || amount_a >= resolves.amount {
+            panic_with_error!(&env, EscrowError::InvalidAmount);
+        }
+
+        // Require taker's auth
+        resolves.taker.require_auth();
+
+        let mut split = immutables.clone();
+        split.safety_deposit = SafetyDeposit::Flat(0);
+        split.extra_safety_deposits = vec![&env];
+        let address = EscrowFactoryClient::new(&env, &resolves.factory).deploy_split(
+            &env.current_contract_address(),
+            &resolves,
+            &split,
+            &amount_a,
+            &nonce,
+            &taker_b,
+        );
+
+        // Transfer the split off amount
+        token::Client::new(&env, &immutables.token).transfer(
+            &env.current_contract_address(),
+            &address,
+            &amount_a,
+        );
+
+        // Update resolves
+        resolves.amount -= amount_a;
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "resolves"), &resolves);
+        Self::bump_ttl(&env);
+
+        // Emit event
+        env.events()
+            .publish(Self::topic(&env, "split"), (address.clone(), amount_a));
+
+        address
+    }
+
+    // Reprice a stale escrow at the current auction level, never above the locked
+    // amount, refunding the difference to the funder, taker only
+    pub fn reprice(env: Env) {
+        let immutables = Self::get_immutables(env.clone());
+        let mut resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Validate time, an escrow without amount validity never goes stale
+        match resolves.amount_valid_until {
+            Some(valid_until) if env.ledger().timestamp() > valid_until => {}
+            _ => panic_with_error!(&env, EscrowError::TooEarly),
+        }
+
+        // Require taker's auth
+        resolves.taker.require_auth();
+
+        let now = Self::auction_now(&env, &resolves);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "last_seen_timestamp"), &now);
+        // Reprice the order as a whole, the escrow keeping its share of it once split
+        let priced_amount = immutables.amount.calc(now).min(resolves.priced_amount);
+        let amount = Self::share(&env, &resolves, priced_amount);
+        let funder = match immutables.direction {
+            EscrowDirection::Maker2Taker => &immutables.maker,
+            EscrowDirection::Taker2Maker => &resolves.taker,
+        };
+
+        // Refund the difference
+        if amount < resolves.amount {
+            token::Client::new(&env, &immutables.token).transfer(
+                &env.current_contract_address(),
+                funder,
+                &(resolves.amount - amount),
+            );
+        }
+
+        // Update resolves
+        resolves.amount = amount;
+        resolves.priced_amount = priced_amount;
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "resolves"), &resolves);
+        Self::bump_ttl(&env);
+
+        // Emit event
+        env.events()
+            .publish(Self::topic(&env, "reprice"), (amount,));
+    }
+
+    // Replace the hashlock before withdrawal opens, with consent of both parties. The
+    // immutables keep the hashlock the order was signed with
+    pub fn amend_hashlock(env: Env, new_hashlock: BytesN<32>, maker: Address, taker: Address) {
+        let immutables: EscrowImmutables = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "immutables"))
+            .unwrap();
+
+        let mut resolves: EscrowResolves = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "resolves"))
+            .unwrap();
+
+        let state: EscrowState = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "state"))
+            .unwrap();
+
+        // Validate state
+        if !matches!(state, EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Validate parties
+        if maker != immutables.maker || taker != resolves.taker {
+            panic_with_error!(&env, EscrowError::Unauthorized);
+        }
+
+        // Validate time, amendment is only possible before anyone can withdraw
+        let start = resolves.timestamp.saturating_add(resolves.withdrawal);
+        if env.ledger().timestamp() >= start {
+            panic_with_error!(&env, EscrowError::TooLate);
+        }
+
+        // Validate the hashlock is set, as checked at creation
+        if new_hashlock == BytesN::from_array(&env, &[0; 32]) {
+            panic_with_error!(&env, EscrowError::ZeroHashlock);
+        }
+
+        // Require both parties' auth
+        maker.require_auth();
+        taker.require_auth();
+
+        resolves.hashlock = new_hashlock.clone();
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "resolves"), &resolves);
+        Self::bump_ttl(&env);
+
+        // Emit event
+        env.events()
+            .publish(Self::topic(&env, "amend_hashlock"), (new_hashlock,));
+    }
+
+    // Open the taker's withdrawal window now, with consent of both parties. The
+    // immutables keep the timelocks the order was signed with
+    pub fn open_withdrawal_early(env: Env, maker: Address, taker: Address) {
+        let immutables = Self::get_immutables(env.clone());
+        let mut resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Validate parties
+        if maker != immutables.maker || taker != resolves.taker {
+            panic_with_error!(&env, EscrowError::Unauthorized);
+        }
+
+        // Require both parties' auth
+        maker.require_auth();
+        taker.require_auth();
+
+        // Shorten the withdrawal timelock to the time elapsed, never extending it
+        let elapsed = env.ledger().timestamp().saturating_sub(resolves.timestamp);
+        resolves.withdrawal = resolves.withdrawal.min(elapsed);
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "resolves"), &resolves);
+        Self::bump_ttl(&env);
+
+        // Emit event
+        env.events()
+            .publish(Self::topic(&env, "open_withdrawal_early"), ());
+    }
+
+    // Get escrow immutables
+    pub fn get_immutables(env: Env) -> EscrowImmutables {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "immutables"))
+            .unwrap()
+    }
+
+    // Whether the escrow was created with the claimed immutables, comparing hashes of
+    // their canonical encodings so only a bool crosses the contract boundary. The
+    // stored side is hashed once at initialization
+    pub fn verify_immutables(env: Env, claimed: EscrowImmutables) -> bool {
+        let stored: BytesN<32> = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "immutables_hash"))
+            .unwrap();
+        stored == env.crypto().sha256(&claimed.encode(&env)).to_bytes()
+    }
+
+    // Get the XDR serialization of the stored immutables, for byte-exact hashing off-chain
+    pub fn immutables_xdr(env: Env) -> Bytes {
+        Self::get_immutables(env.clone()).to_xdr(&env)
+    }
+
+    // Get escrow timelocks in effect, withdrawal as opened early if so
+    pub fn get_timelocks(env: Env) -> TimeLocks {
+        TimeLocks {
+            withdrawal: Self::get_resolves(env.clone()).withdrawal,
+            ..Self::get_immutables(env).timelocks
+        }
+    }
+
+    // Get the direction funds flow in, for clients that only branch on it
+    pub fn get_direction(env: Env) -> EscrowDirection {
+        Self::get_immutables(env).direction
+    }
+
+    // Get escrow resolves
+    pub fn get_resolves(env: Env) -> EscrowResolves {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "resolves"))
+            .unwrap()
+    }
+
+    // Link the escrow on the other side of the swap, requiring it to be active and
+    // funded for withdrawals when set. Only the payee of the principal, whose
+    // withdrawal the check can hold back, may change it
+    pub fn set_counterparty(env: Env, counterparty: Option<Address>, require_funded: bool) {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        // Validate state
+        if !matches!(Self::get_state(env.clone()), EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Require payee's auth
+        let payee = match immutables.direction {
+            EscrowDirection::Maker2Taker => resolves.taker,
+            EscrowDirection::Taker2Maker => immutables.maker,
+        };
+        payee.require_auth();
+
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "counterparty"), &counterparty);
+        env.storage().instance().set(
+            &Symbol::new(&env, "require_counterparty_funded"),
+            &require_funded,
+        );
+        Self::bump_ttl(&env);
+    }
+
+    // Get the linked counterparty escrow, if any
+    pub fn counterparty(env: Env) -> Option<Address> {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "counterparty"))
+            .unwrap_or(None)
+    }
+
+    // Whether withdrawals require the counterparty escrow to be funded
+    pub fn require_counterparty_funded(env: Env) -> bool {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "require_counterparty_funded"))
+            .unwrap_or(false)
+    }
+
+    // Get the secret revealed by the withdrawal, if any
+    pub fn revealed_secret(env: Env) -> Option<Bytes> {
+        env.storage().instance().get(&Symbol::new(&env, "secret"))
+    }
+
+    // Check whether a secret has an acceptable shape before attempting a withdrawal:
+    // non-empty and no longer than MAX_SECRET_LEN
+    pub fn is_valid_secret_format(_env: Env, secret: Bytes) -> bool {
+        !secret.is_empty() && secret.len() <= MAX_SECRET_LEN
+    }
+
+    // Get the algorithm secrets of the escrow are hashed with
+    pub fn get_hash_algo(env: Env) -> HashAlgo {
+        Self::get_immutables(env).hash_algo
+    }
+
+    // Get escrow state
+    pub fn get_state(env: Env) -> EscrowState {
+        env.storage()
+            .instance()
+            .get(&Symbol::new(&env, "state"))
+            .unwrap()
+    }
+
+    // Get the locked amount and the current quote of the amount calculation, scaled to
+    // the escrow's share of the order once split
+    pub fn implied_rate(env: Env) -> (i128, i128) {
+        let immutables: EscrowImmutables = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "immutables"))
+            .unwrap();
+
+        let resolves: EscrowResolves = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "resolves"))
+            .unwrap();
+
+        let quote = immutables.amount.calc(Self::auction_now(&env, &resolves));
+        (resolves.amount, Self::share(&env, &resolves, quote))
+    }
+
+    // Get how the escrow's balance of its token splits between the principal and
+    // the safety deposits made in that token, for escrows sharing one token
+    pub fn deposit_split(env: Env) -> (i128, i128) {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        let (amount_held, deposits_held) = match Self::get_state(env.clone()) {
+            EscrowState::Active => (true, true),
+            EscrowState::Pending | EscrowState::Frozen => (true, false),
+            EscrowState::Withdrawn | EscrowState::Cancelled | EscrowState::Settling => {
+                (false, false)
+            }
+        };
+
+        let mut deposit = 0;
+        if deposits_held {
+            for (deposit_token, deposit_amount) in Self::deposits(&env, &immutables, &resolves) {
+                if deposit_token == immutables.token {
+                    deposit += deposit_amount;
+                }
+            }
+        }
+        (if amount_held { resolves.amount } else { 0 }, deposit)
+    }
+
+    // Get the part of the safety deposit a public caller settling at the given time
+    // is rewarded with, growing with how overdue the settlement is
+    pub fn reward_for(env: Env, now: u64) -> i128 {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env);
+
+        Self::reward(
+            &immutables,
+            &resolves,
+            Self::primary_deposit(&resolves),
+            now,
+        )
+    }
+
+    // Get the time-weighted average amount of the order's auction over its full window,
+    // to compare the locked amount against
+    pub fn auction_twap(env: Env) -> i128 {
+        Self::get_immutables(env).amount.twap()
+    }
+
+    // Get the locked principal against the primary safety deposit, escrows don't store
+    // the amount of their cross-chain counterpart so it's the only ratio known on-chain
+    pub fn effective_rate(env: Env) -> (i128, i128) {
+        let resolves = Self::get_resolves(env);
+
+        (resolves.amount, Self::primary_deposit(&resolves))
+    }
+
+    // Get the latest timelock window opened, whatever the escrow's state
+    pub fn phase(env: Env) -> EscrowPhase {
+        let timelocks = Self::get_timelocks(env.clone());
+        let created = Self::get_resolves(env.clone()).timestamp;
+        let now = env.ledger().timestamp();
+        let opened = |delay: u64| now >= created.saturating_add(delay);
+
+        if opened(timelocks.public_cancellation) && opened(timelocks.cancellation) {
+            EscrowPhase::PublicCancellation
+        } else if opened(timelocks.cancellation) {
+            EscrowPhase::Cancellation
+        } else if opened(timelocks.public_withdrawal) {
+            EscrowPhase::PublicWithdrawal
+        } else if opened(timelocks.withdrawal) {
+            EscrowPhase::Withdrawal
+        } else {
+            EscrowPhase::Locked
+        }
+    }
+
+    // Get the nearest timelock boundary still ahead, 0 once every window has opened
+    pub fn next_action_time(env: Env) -> u64 {
+        let timelocks = Self::get_timelocks(env.clone());
+        let created = Self::get_resolves(env.clone()).timestamp;
+        let now = env.ledger().timestamp();
+
+        let mut next = 0;
+        for delay in [
+            timelocks.withdrawal,
+            timelocks.public_withdrawal,
+            timelocks.cancellation,
+            timelocks.public_cancellation,
+        ] {
+            let boundary = created.saturating_add(delay);
+            if boundary > now && (next == 0 || boundary < next) {
+                next = boundary;
+            }
+        }
+        next
+    }
+
+    // Get the role of the caller, the taker using the private timelocks and anyone else the public ones
+    pub fn caller_role(env: Env, caller: Address) -> CallerRole {
+        if caller == Self::get_resolves(env.clone()).taker {
+            CallerRole::Taker
+        } else if caller == Self::get_immutables(env).maker {
+            CallerRole::Maker
+        } else {
+            CallerRole::Public
+        }
+    }
+
+    // Get where a withdrawal by the caller now would send the principal, what the caller
+    // would receive of each safety deposit and whether it can happen
+    pub fn caller_economics(env: Env, caller: Address) -> CallerEconomics {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+
+        let payee = match immutables.direction {
+            EscrowDirection::Maker2Taker => resolves.taker.clone(),
+            EscrowDirection::Taker2Maker => immutables.maker.clone(),
+        };
+
+        let mut safety_deposits = vec![&env];
+        let shares = Self::deposit_shares(&env, &immutables, &resolves, &caller, None);
+        for (deposit_token, _, reward) in shares.iter() {
+            safety_deposits.push_back((deposit_token, reward));
+        }
+
+        CallerEconomics {
+            payee,
+            safety_deposits,
+            can_withdraw: matches!(Self::get_state(env.clone()), EscrowState::Active)
+                && Self::withdrawal_open(&env, &immutables, &resolves, &caller),
+        }
+    }
+
+    // Get escrow logic version
+    pub fn version(_env: Env) -> u32 {
+        VERSION
+    }
+}
+
+impl Escrow {
+    // Event topic under the prefix of the factory deployment
+    fn topic(env: &Env, name: &str) -> (Symbol, Symbol) {
+        (
+            Self::get_resolves(env.clone()).topic_prefix,
+            Symbol::new(env, name),
+        )
+    }
+
+    // Extend the escrow storage lifetime by the bump configured at creation
+    fn bump_ttl(env: &Env) {
+        let ttl_bump = Self::get_resolves(env.clone()).ttl_bump;
+        env.storage().instance().extend_ttl(ttl_bump, ttl_bump);
+    }
+
+    // Current time on the auction clock, never before the last time the amount was
+    // recomputed at, so a clock moving backward can't regress the amount
+    fn auction_now(env: &Env, resolves: &EscrowResolves) -> u64 {
+        let now = resolves.clock_source.now(env);
+        let last_seen: u64 = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(env, "last_seen_timestamp"))
+            .unwrap_or(0);
+        now.max(last_seen)
+    }
+
+    // Share of an amount priced for the whole order that falls to the escrow, rounded
+    // down, the escrow locking its share of the priced amount once split
+    fn share(env: &Env, resolves: &EscrowResolves, order_amount: i128) -> i128 {
+        if resolves.priced_amount == 0 {
+            return 0;
+        }
+        order_amount
+            .checked_mul(resolves.amount)
+            .unwrap_or_else(|| panic_with_error!(env, EscrowError::AmountOverflow))
+            / resolves.priced_amount
+    }
+
+    // Reward out of a deposit for a public caller at the given time, scaling linearly
+    // with the time since the public window opened up to the full deposit
+    fn reward(
+        immutables: &EscrowImmutables,
+        resolves: &EscrowResolves,
+        deposit: i128,
+        now: u64,
+    ) -> i128 {
+        if resolves.reward_ramp == 0 {
+            return deposit;
+        }
+
+        // Public cancellation once open, otherwise public withdrawal
+        let public_cancellation = resolves
+            .timestamp
+            .saturating_add(immutables.timelocks.public_cancellation)
+            .max(
+                resolves
+                    .timestamp
+                    .saturating_add(immutables.timelocks.cancellation),
+            );
+        let opened = if now >= public_cancellation {
+            public_cancellation
+        } else {
+            resolves
+                .timestamp
+                .saturating_add(immutables.timelocks.public_withdrawal)
+        };
+
+        let overdue = now.saturating_sub(opened).min(resolves.reward_ramp);
+        deposit * overdue as i128 / resolves.reward_ramp as i128
+    }
+
+    // Return the funds to whoever locked them and the safety deposits to the caller,
+    // or only the keeper reward if given
+    fn refund(
+        env: &Env,
+        immutables: &EscrowImmutables,
+        resolves: &EscrowResolves,
+        caller: &Address,
+        keeper_reward: Option<i128>,
+    ) {
+        let payee = match immutables.direction {
+            EscrowDirection::Maker2Taker => &immutables.maker,
+            EscrowDirection::Taker2Maker => &resolves.taker,
+        };
+
+        // Transfer tokens back and safety deposits to caller, settling meanwhile
+        let factory = EscrowFactoryClient::new(env, &resolves.factory);
+        factory.begin_settlement(&env.current_contract_address(), &resolves.hashlock);
+        Self::set_settling(env);
+        Self::pay_out(
+            env,
+            immutables,
+            resolves,
+            Some((payee, resolves.amount)),
+            caller,
+            keeper_reward,
+        );
+
+        // Update state
+        env.storage()
+            .instance()
+            .set(&Symbol::new(env, "state"), &EscrowState::Cancelled);
+        env.storage()
+            .instance()
+            .remove(&Symbol::new(env, "stuck_since"));
+        Self::bump_ttl(env);
+        Self::report_settlement(env, false);
+        factory.end_settlement(&env.current_contract_address(), &resolves.hashlock);
+
+        // Emit event
+        env.events().publish(
+            Self::topic(env, "cancel"),
+            (immutables.refund_address_hint.clone(),),
+        );
+    }
+
+    // Safety deposits held by the escrow, none when created with the mechanism disabled
+    fn deposits(
+        env: &Env,
+        immutables: &EscrowImmutables,
+        resolves: &EscrowResolves,
+    ) -> Vec<(Address, i128)> {
+        if resolves.no_safety_deposit {
+            vec![env]
+        } else {
+            let mut deposits = vec![
+                env,
+                (
+                    immutables.safety_deposit_token.clone(),
+                    resolves.safety_deposit,
+                ),
+            ];
+            deposits.append(&immutables.extra_safety_deposits);
+            deposits
+        }
+    }
+
+    // Primary safety deposit held by the escrow
+    fn primary_deposit(resolves: &EscrowResolves) -> i128 {
+        if resolves.no_safety_deposit {
+            0
+        } else {
+            resolves.safety_deposit
+        }
+    }
+
+    // Whether the caller is the taker or its backup, who settle with the taker's rights
+    fn is_resolver(
+        immutables: &EscrowImmutables,
+        resolves: &EscrowResolves,
+        caller: &Address,
+    ) -> bool {
+        *caller == resolves.taker || immutables.backup_taker.as_ref() == Some(caller)
+    }
+
+    // Mark the escrow settling for the duration of its payout transfers
+    fn set_settling(env: &Env) {
+        env.storage()
+            .instance()
+            .set(&Symbol::new(env, "state"), &EscrowState::Settling);
+    }
+
+    // Pay the principal to the payee, if given, and the safety deposits to the caller,
+    // a public caller getting its reward and the taker the rest. A keeper reward, if
+    // given, replaces the caller's reward with that much of the primary deposit.
+    // Deposits in the principal token bound for the payee are folded into the
+    // principal transfer
+    fn pay_out(
+        env: &Env,
+        immutables: &EscrowImmutables,
+        resolves: &EscrowResolves,
+        payout: Option<(&Address, i128)>,
+        caller: &Address,
+        keeper_reward: Option<i128>,
+    ) {
+        let sender = env.current_contract_address();
+        env.storage()
+            .instance()
+            .set(&Symbol::new(env, "deposit_recipient"), caller);
+        let payee = payout.map(|(payee, _)| payee);
+        let mut principal = payout.map_or(0, |(_, principal)| principal);
+        let shares = Self::deposit_shares(env, immutables, resolves, caller, keeper_reward);
+        for (deposit_token, deposit_amount, reward) in shares.iter() {
+            for (recipient, amount) in
+                [(caller, reward), (&resolves.taker, deposit_amount - reward)]
+            {
+                if amount <= 0 {
+                    continue;
+                }
+                if deposit_token == immutables.token && payee == Some(recipient) {
+                    principal += amount;
+                } else {
+                    token::Client::new(env, &deposit_token).transfer(&sender, recipient, &amount);
+                }
+            }
+        }
+        if let Some(payee) = payee {
+            token::Client::new(env, &immutables.token).transfer(&sender, payee, &principal);
+        }
+    }
+
+    // Safety deposits as (token, amount, part paid to the caller), the rest going to
+    // the taker. Keepers get the fixed keeper reward out of the primary deposit only
+    fn deposit_shares(
+        env: &Env,
+        immutables: &EscrowImmutables,
+        resolves: &EscrowResolves,
+        caller: &Address,
+        keeper_reward: Option<i128>,
+    ) -> Vec<(Address, i128, i128)> {
+        let now = env.ledger().timestamp();
+        let mut shares = vec![env];
+        let deposits = Self::deposits(env, immutables, resolves);
+        for (i, (deposit_token, deposit_amount)) in deposits.iter().enumerate() {
+            let reward = match keeper_reward {
+                Some(keeper_reward) if i == 0 => keeper_reward.clamp(0, deposit_amount),
+                Some(_) => 0,
+                None if Self::is_resolver(immutables, resolves, caller) => deposit_amount,
+                None => Self::reward(immutables, resolves, deposit_amount, now),
+            };
+            let reward = Self::fold_dust(env, resolves, &deposit_token, deposit_amount, reward);
+            shares.push_back((deposit_token, deposit_amount, reward));
+        }
+        shares
+    }
+
+    // Part of a deposit paid to the caller, the rest going to the taker, with a part
+    // below the token's minimum transfer folded into the other
+    fn fold_dust(
+        env: &Env,
+        resolves: &EscrowResolves,
+        token: &Address,
+        deposit: i128,
+        reward: i128,
+    ) -> i128 {
+        let min_transfer = EscrowFactoryClient::new(env, &resolves.factory).min_transfer(token);
+        if reward < min_transfer {
+            0
+        } else if deposit - reward < min_transfer {
+            deposit
+        } else {
+            reward
+        }
+    }
+
+    // Send the protocol fee on the principal to the factory, returning the amount sent.
+    // A fee below the token's minimum transfer is waived
+    fn charge_fee(env: &Env, immutables: &EscrowImmutables, resolves: &EscrowResolves) -> i128 {
+        let factory = EscrowFactoryClient::new(env, &resolves.factory);
+        let fee = resolves.protocol_fee.calc(env, resolves.amount);
+        if fee <= 0 || fee < factory.min_transfer(&immutables.token) {
+            return 0;
+        }
+        token::Client::new(env, &immutables.token).transfer(
+            &env.current_contract_address(),
+            &resolves.factory,
+            &fee,
+        );
+        factory.record_fee(&env.current_contract_address(), &immutables.token, &fee);
+        fee
+    }
+
+    // Report the principal settled by the escrow to the factory volume stats
+    fn report_settlement(env: &Env, withdrawn: bool) {
+        let immutables = Self::get_immutables(env.clone());
+        let resolves = Self::get_resolves(env.clone());
+        EscrowFactoryClient::new(env, &resolves.factory).record_settlement(
+            &env.current_contract_address(),
+            &immutables.token,
+            &resolves.amount,
+            &withdrawn,
+        );
+    }
+
+    // Report the withdrawal of a segment escrow to the factory and emit the fill event
+    fn report_fill(env: &Env) {
+        let resolves = Self::get_resolves(env.clone());
+        if let Some(index) = resolves.segment_index {
+            let withdrawn = EscrowFactoryClient::new(env, &resolves.factory).record_fill(
+                &env.current_contract_address(),
+                &resolves.order_hashlock,
+                &resolves.amount,
+                &Self::revealed_secret(env.clone()).unwrap(),
+            );
+            env.events().publish(
+                (
+                    resolves.topic_prefix,
+                    Symbol::new(env, "withdraw_fill"),
+                    resolves.order_hashlock,
+                ),
+                (index, resolves.amount, withdrawn),
+            );
+        }
+    }
+
+    // Validate and settle a withdrawal, every configured hashlock needing its secret
+    fn settle_withdraw(env: Env, secrets: Vec<Bytes>, caller: Address) {
+        let immutables: EscrowImmutables = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "immutables"))
+            .unwrap();
+
+        let resolves: EscrowResolves = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "resolves"))
+            .unwrap();
+
+        let state: EscrowState = env
+            .storage()
+            .instance()
+            .get(&Symbol::new(&env, "state"))
+            .unwrap();
+
+        let sender = env.current_contract_address();
+
+        let payee = match immutables.direction {
+            EscrowDirection::Maker2Taker => &resolves.taker,
+            EscrowDirection::Taker2Maker => &immutables.maker,
+        };
+
+        // Validate state
+        if !matches!(state, EscrowState::Active) {
+            panic_with_error!(&env, EscrowError::NotActive);
+        }
+
+        // Require caller's auth, as who they are decides their window, the deposits
+        // they're paid and the rebate
+        caller.require_auth();
+
+        // Validate time
+        if !Self::withdrawal_open(&env, &immutables, &resolves, &caller) {
+            panic_with_error!(&env, EscrowError::TooEarly);
+        }
+
+        // Validate the linked counterparty escrow is still funded, if required
+        if Self::require_counterparty_funded(env.clone()) {
+            if let Some(counterparty) = Self::counterparty(env.clone()) {
+                let counterparty = EscrowClient::new(&env, &counterparty);
+                let counterparty_immutables = counterparty.get_immutables();
+                let counterparty_amount = counterparty.get_resolves().amount;
+                let funded = matches!(counterparty.get_state(), EscrowState::Active)
+                    && token::Client::new(&env, &counterparty_immutables.token)
+                        .balance(&counterparty.address)
+                        >= counterparty_amount;
+                if !funded {
+                    panic_with_error!(&env, EscrowError::CounterpartyNotFunded);
+                }
+            }
+        }
+
+        let factory = EscrowFactoryClient::new(&env, &resolves.factory);
+        let timestamp = env.ledger().timestamp();
+
+        // Rate limit public callers, per caller as they authenticated above
+        if !Self::is_resolver(&immutables, &resolves, &caller) {
+            factory.record_public_withdrawal(&env.current_contract_address(), &caller);
+        }
+
+        // Validate secrets, one per configured hashlock
+        let mut hashlocks = vec![&env, resolves.hashlock.clone()];
+        if let Some(second_hashlock) = immutables.second_hashlock.clone() {
+            hashlocks.push_back(second_hashlock);
+        }
+        if secrets.len() != hashlocks.len() {
+            panic_with_error!(&env, EscrowError::InvalidSecret);
+        }
+        for (secret, hashlock) in secrets.iter().zip(hashlocks.iter()) {
+            if !Self::is_valid_secret_format(env.clone(), secret.clone())
+                || immutables.hash_algo.hash(&env, &secret) != hashlock
+            {
+                panic_with_error!(&env, EscrowError::InvalidSecret);
+            }
+        }
+
+        // Validate the escrow funds every transfer itself, so the caller needs no balance
+        let mut outflows = Self::deposits(&env, &immutables, &resolves);
+        outflows.push_back((immutables.token.clone(), resolves.amount));
+        for (outflow_token, _) in outflows.iter() {
+            let mut required = 0;
+            for (token, amount) in outflows.iter() {
+                if token == outflow_token {
+                    required += amount;
+                }
+            }
+            if token::Client::new(&env, &outflow_token).balance(&sender) < required {
+                panic_with_error!(&env, EscrowError::Underfunded);
+            }
+        }
+
+        // Mark the escrow settling so a call re-entering it mid-transfer finds it
+        // inactive, on top of the host rejecting contract re-entry outright, and its
+        // hashlock settling so no sibling escrow is cancelled before the reveal
+        factory.begin_settlement(&env.current_contract_address(), &resolves.hashlock);
+        Self::set_settling(&env);
+
+        // Transfer tokens, unless held for the dispute window, and safety deposits to caller
+        Self::pay_out(
+            &env,
+            &immutables,
+            &resolves,
+            (resolves.dispute_window == 0).then(|| {
+                (
+                    payee,
+                    resolves.amount - Self::charge_fee(&env, &immutables, &resolves),
+                )
+            }),
+            &caller,
+            None,
+        );
+
+        // Reward the taker settling promptly, once per fill
+        if resolves.rebate && caller == resolves.taker {
+            factory.pay_rebate(
+                &env.current_contract_address(),
+                &caller,
+                &resolves.timestamp.saturating_add(resolves.withdrawal),
+            );
+        }
+
+        // Update state
+        if resolves.dispute_window == 0 {
+            env.storage()
+                .instance()
+                .set(&Symbol::new(&env, "state"), &EscrowState::Withdrawn);
+        } else {
+            env.storage().instance().set(
+                &Symbol::new(&env, "pending_until"),
+                &timestamp.saturating_add(resolves.dispute_window),
+            );
+            env.storage()
+                .instance()
+                .set(&Symbol::new(&env, "state"), &EscrowState::Pending);
+        }
+        Self::bump_ttl(&env);
+        Self::report_settlement(&env, true);
+
+        // Keep the secret readable by contracts on this chain
+        env.storage()
+            .instance()
+            .set(&Symbol::new(&env, "secret"), &secrets.get_unchecked(0));
+
+        // Share the secrets with the factory so sibling escrows can't be cancelled
+        for secret in secrets.iter() {
+            factory.reveal(
+                &env.current_contract_address(),
+                &secret,
+                &immutables.hash_algo,
+            );
+        }
+
+        factory.end_settlement(&env.current_contract_address(), &resolves.hashlock);
+
+        // Push the secrets to the registry, if any, for keepers settling siblings
+        if let Some(registry) = &resolves.secret_registry {
+            let registry = SecretRegistryClient::new(&env, registry);
+            for (secret, hashlock) in secrets.iter().zip(hashlocks.iter()) {
+                registry.push_secret(&hashlock, &secret);
+            }
+        }
+    }
+
+    // Whether every secret the escrow is locked by has been revealed. A sibling sharing
+    // only the first hashlock can reveal that secret alone, which mustn't block
+    // cancellation while withdrawal still needs the unknown second one
+    fn secrets_revealed(
+        env: &Env,
+        immutables: &EscrowImmutables,
+        resolves: &EscrowResolves,
+    ) -> bool {
+        let factory = EscrowFactoryClient::new(env, &resolves.factory);
+        factory.is_revealed(&resolves.hashlock)
+            && immutables
+                .second_hashlock
+                .as_ref()
+                .is_none_or(|hashlock| factory.is_revealed(hashlock))
+    }
+
+    // Whether the withdrawal window of the caller is open, a window starting past
+    // u64::MAX never opens. Withdrawal closes when the taker's cancellation opens,
+    // unless the secrets were revealed through sibling escrows, which blocks
+    // cancellation for good and leaves withdrawal as the way out
+    fn withdrawal_open(
+        env: &Env,
+        immutables: &EscrowImmutables,
+        resolves: &EscrowResolves,
+        caller: &Address,
+    ) -> bool {
+        let start = resolves
+            .timestamp
+            .checked_add(if *caller == resolves.taker {
+                resolves.withdrawal
+            } else if immutables.backup_taker.as_ref() == Some(caller) {
+                immutables.backup_withdrawal
+            } else {
+                immutables.timelocks.public_withdrawal
+            });
+        let end = resolves
+            .timestamp
+            .saturating_add(immutables.timelocks.cancellation);
+        let timestamp = env.ledger().timestamp();
+        matches!(start, Some(start) if timestamp >= start)
+            && (timestamp < end || Self::secrets_revealed(env, immutables, resolves))
+    }
+}
@@ -64,6 +64,23 @@ impl Timelocks {
     pub fn rescue_start(&self, rescue_delay: u32) -> u32 {
         self.deployed_at() + rescue_delay
     }
+
+    /// Gets the absolute time when the given stage starts as a ledger timestamp,
+    /// widened to u64 so deployed_at + delay can't overflow
+    pub fn get_timestamp(&self, stage: Stage) -> u64 {
+        let idx: u32 = stage.into();
+        u64::from(self.deployed_at()) + u64::from(self.0.get(idx).unwrap_or(0))
+    }
+
+    /// Checks whether the given stage has started at a ledger timestamp
+    pub fn has_started(&self, stage: Stage, timestamp: u64) -> bool {
+        timestamp >= self.get_timestamp(stage)
+    }
+
+    /// Computes the start of the rescue period as a ledger timestamp
+    pub fn rescue_start_timestamp(&self, rescue_delay: u32) -> u64 {
+        u64::from(self.deployed_at()) + u64::from(rescue_delay)
+    }
 }
 
 mod test;
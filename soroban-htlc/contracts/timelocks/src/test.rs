@@ -16,4 +16,20 @@ fn test_timelocks() {
     assert_eq!(timelocks.get(Stage::SrcWithdrawal), 1_000_300);
     assert_eq!(timelocks.get(Stage::DstWithdrawal), 1_000_600);
     assert_eq!(timelocks.rescue_start(1000), 1_001_000);
+}
+
+#[test]
+fn test_timelocks_past_u32_range() {
+    let env = Env::default();
+
+    let deployed_at = u32::MAX - 100;
+    let mut timelocks = Timelocks::new(&env, deployed_at);
+
+    timelocks.set_stage(Stage::SrcWithdrawal, 300);
+
+    let start = u32::MAX as u64 + 200;
+    assert_eq!(timelocks.get_timestamp(Stage::SrcWithdrawal), start);
+    assert!(!timelocks.has_started(Stage::SrcWithdrawal, start - 1));
+    assert!(timelocks.has_started(Stage::SrcWithdrawal, start));
+    assert_eq!(timelocks.rescue_start_timestamp(u32::MAX), deployed_at as u64 + u32::MAX as u64);
 }
\ No newline at end of file